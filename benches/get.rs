@@ -0,0 +1,202 @@
+//! Compares the read throughput of `StreamCache::get` with a bare
+//! `Mutex<HashMap>`, which the cache's store is built on, while both are
+//! updated at the same rate of [`WRITES_PER_SEC`] concurrently. This is the
+//! baseline any store designed for concurrent reads has to beat.
+//!
+//! Such a store only pays off when readers run in parallel, so numbers have
+//! to be taken on a machine with more cores than readers plus the writer.
+//! Rows where that is not the case are marked.
+//!
+//! Run with `cargo bench --bench get`.
+
+use std::{
+    collections::HashMap,
+    hint::black_box,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use futures::{stream::BoxStream, StreamExt};
+use streamed_cache::{Api, ApiError, City, Delta, StreamCache, Temperature};
+use tokio::{runtime, task};
+
+const CITIES: usize = 1_000;
+const RUN: Duration = Duration::from_millis(500);
+const WRITES_PER_SEC: f64 = 100_000.0;
+
+fn city(i: usize) -> City {
+    format!("City {i}")
+}
+
+/// Number of writes due since `start` at [`WRITES_PER_SEC`].
+fn writes_due(start: Instant) -> u64 {
+    (start.elapsed().as_secs_f64() * WRITES_PER_SEC) as u64
+}
+
+/// Upstream that streams updates to all cities at [`WRITES_PER_SEC`].
+#[derive(Default)]
+struct BenchApi {
+    streamed: Arc<AtomicU64>,
+}
+
+#[async_trait]
+impl Api for BenchApi {
+    async fn fetch(&self) -> Result<HashMap<City, Temperature>, ApiError> {
+        Ok((0..CITIES)
+            .map(|i| (city(i), Temperature::celsius(0.0)))
+            .collect())
+    }
+
+    async fn subscribe(&self) -> BoxStream<Result<Delta, ApiError>> {
+        let start = Instant::now();
+        let streamed = self.streamed.clone();
+        futures::stream::unfold(0, move |n| {
+            let streamed = streamed.clone();
+            async move {
+                // Let the runtime check whether the benchmark is over, and
+                // give up the CPU like the mutex writer while none is due.
+                task::yield_now().await;
+                while n >= writes_due(start) {
+                    thread::yield_now();
+                    task::yield_now().await;
+                }
+                streamed.fetch_add(1, Ordering::Relaxed);
+                Some((
+                    Ok(Delta::Upsert(
+                        city(n as usize % CITIES),
+                        Temperature::celsius(n as f64),
+                    )),
+                    n + 1,
+                ))
+            }
+        })
+        .boxed()
+    }
+}
+
+/// Runs `get` on `readers` threads for [`RUN`] while `write` runs on one more
+/// thread until the readers are done. Returns the total reads per second and
+/// the writes per second, as counted by `write`.
+fn measure(
+    readers: usize,
+    get: impl Fn(&str) + Sync,
+    write: impl FnOnce(&AtomicBool) -> u64 + Send,
+) -> (f64, f64) {
+    let keys: Vec<City> = (0..CITIES).map(city).collect();
+    let done = AtomicBool::new(false);
+    let (reads, writes) = thread::scope(|scope| {
+        let done = &done;
+        let writer = scope.spawn(move || write(done));
+        let handles: Vec<_> = (0..readers)
+            .map(|reader| {
+                let (keys, get) = (&keys, &get);
+                scope.spawn(move || {
+                    let start = Instant::now();
+                    let mut reads = 0u64;
+                    let mut i = reader;
+                    while start.elapsed() < RUN {
+                        for _ in 0..1_000 {
+                            get(&keys[i % CITIES]);
+                            i += 7;
+                        }
+                        reads += 1_000;
+                    }
+                    reads
+                })
+            })
+            .collect();
+        let reads: u64 = handles.into_iter().map(|h| h.join().unwrap()).sum();
+        done.store(true, Ordering::Relaxed);
+        (reads, writer.join().unwrap())
+    });
+    (
+        reads as f64 / RUN.as_secs_f64(),
+        writes as f64 / RUN.as_secs_f64(),
+    )
+}
+
+fn mutex(readers: usize) -> (f64, f64) {
+    let map: Mutex<HashMap<City, Temperature>> = Mutex::new(
+        (0..CITIES)
+            .map(|i| (city(i), Temperature::celsius(0.0)))
+            .collect(),
+    );
+    measure(
+        readers,
+        |key| {
+            black_box(map.lock().unwrap().get(key).copied());
+        },
+        |done| {
+            let start = Instant::now();
+            let mut n = 0;
+            while !done.load(Ordering::Relaxed) {
+                if n >= writes_due(start) {
+                    thread::yield_now();
+                    continue;
+                }
+                map.lock()
+                    .unwrap()
+                    .insert(city(n as usize % CITIES), Temperature::celsius(n as f64));
+                n += 1;
+            }
+            n
+        },
+    )
+}
+
+fn stream_cache(readers: usize) -> (f64, f64) {
+    let rt = runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap();
+    let api = BenchApi::default();
+    let streamed = api.streamed.clone();
+    let cache = {
+        let _guard = rt.enter();
+        StreamCache::new(api)
+    };
+    rt.block_on(cache.ready());
+    measure(
+        readers,
+        |key| {
+            black_box(cache.get(key));
+        },
+        |done| {
+            let before = streamed.load(Ordering::Relaxed);
+            rt.block_on(async {
+                while !done.load(Ordering::Relaxed) {
+                    task::yield_now().await;
+                }
+            });
+            streamed.load(Ordering::Relaxed) - before
+        },
+    )
+}
+
+fn main() {
+    let cores = thread::available_parallelism().map_or(1, |cores| cores.get());
+    println!("{cores} cores available");
+    println!(
+        "{:>8} {:>16} {:>16} {:>16} {:>16}",
+        "readers", "mutex gets/s", "mutex writes/s", "cache gets/s", "cache writes/s"
+    );
+    let mut oversubscribed = false;
+    for readers in [1, 2, 4, 8] {
+        let (mutex_reads, mutex_writes) = mutex(readers);
+        let (cache_reads, cache_writes) = stream_cache(readers);
+        // the writer needs a core of its own too
+        let mark = if readers < cores { ' ' } else { '*' };
+        oversubscribed |= readers >= cores;
+        println!(
+            "{readers:>7}{mark} {mutex_reads:>16.0} {mutex_writes:>16.0} {cache_reads:>16.0} {cache_writes:>16.0}"
+        );
+    }
+    if oversubscribed {
+        println!("* more threads than cores, so reads did not all run in parallel");
+    }
+}
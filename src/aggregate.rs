@@ -0,0 +1,269 @@
+use std::{cmp::Ordering, collections::BTreeMap};
+
+use crate::Temperature;
+
+/// Values that can be aggregated across keys by
+/// [`StreamCache::aggregate`](crate::StreamCache::aggregate).
+pub trait Numeric {
+    fn to_f64(&self) -> f64;
+}
+
+impl Numeric for f64 {
+    fn to_f64(&self) -> f64 {
+        *self
+    }
+}
+
+impl Numeric for i64 {
+    fn to_f64(&self) -> f64 {
+        *self as f64
+    }
+}
+
+impl Numeric for u64 {
+    fn to_f64(&self) -> f64 {
+        *self as f64
+    }
+}
+
+/// Temperatures are aggregated in degrees Celsius.
+impl Numeric for Temperature {
+    fn to_f64(&self) -> f64 {
+        self.as_celsius()
+    }
+}
+
+/// Summary of all cached values, see [`Numeric`] for their unit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aggregate {
+    pub count: usize,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub median: f64,
+}
+
+/// `f64` ordered by [`f64::total_cmp`], so it can be used as a map key.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Ordered(pub f64);
+
+impl PartialEq for Ordered {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Ordered {}
+
+impl PartialOrd for Ordered {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Ordered {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// Multiset of values with its length.
+#[derive(Debug, Default)]
+struct Bag {
+    counts: BTreeMap<Ordered, usize>,
+    len: usize,
+}
+
+impl Bag {
+    fn add(&mut self, value: Ordered) {
+        *self.counts.entry(value).or_default() += 1;
+        self.len += 1;
+    }
+
+    fn remove(&mut self, value: Ordered) {
+        if let Some(count) = self.counts.get_mut(&value) {
+            *count -= 1;
+            if *count == 0 {
+                self.counts.remove(&value);
+            }
+            self.len -= 1;
+        }
+    }
+
+    fn first(&self) -> Option<Ordered> {
+        self.counts.keys().next().copied()
+    }
+
+    fn last(&self) -> Option<Ordered> {
+        self.counts.keys().next_back().copied()
+    }
+}
+
+/// Aggregates over a changing set of values, updated in `O(log n)`.
+///
+/// The median is tracked by splitting the values into a lower and an upper
+/// half, where the lower half holds the extra value if the count is odd.
+/// Values that are NaN or infinite are left out, as they would poison the
+/// sum for good even after they were removed.
+#[derive(Debug)]
+pub(crate) struct Aggregates<V> {
+    measure: fn(&V) -> f64,
+    lower: Bag,
+    upper: Bag,
+    sum: f64,
+    /// Rounding error lost from `sum`, so that it does not build up over
+    /// many additions and removals (Kahan-Babuška summation).
+    compensation: f64,
+}
+
+impl<V> Aggregates<V> {
+    pub fn new(measure: fn(&V) -> f64) -> Self {
+        Self {
+            measure,
+            lower: Bag::default(),
+            upper: Bag::default(),
+            sum: 0.0,
+            compensation: 0.0,
+        }
+    }
+
+    fn accumulate(&mut self, value: f64) {
+        let sum = self.sum + value;
+        self.compensation += if self.sum.abs() >= value.abs() {
+            (self.sum - sum) + value
+        } else {
+            (value - sum) + self.sum
+        };
+        self.sum = sum;
+    }
+
+    pub fn add(&mut self, value: &V) {
+        let value = Ordered((self.measure)(value));
+        if !value.0.is_finite() {
+            return;
+        }
+        self.accumulate(value.0);
+        if self.lower.last().is_none_or(|max| value <= max) {
+            self.lower.add(value);
+        } else {
+            self.upper.add(value);
+        }
+        self.rebalance();
+    }
+
+    pub fn remove(&mut self, value: &V) {
+        let value = Ordered((self.measure)(value));
+        if !value.0.is_finite() {
+            return;
+        }
+        self.accumulate(-value.0);
+        if self.lower.last().is_some_and(|max| value <= max) {
+            self.lower.remove(value);
+        } else {
+            self.upper.remove(value);
+        }
+        self.rebalance();
+    }
+
+    fn rebalance(&mut self) {
+        if self.lower.len > self.upper.len + 1 {
+            let max = self.lower.last().expect("lower half is not empty");
+            self.lower.remove(max);
+            self.upper.add(max);
+        } else if self.upper.len > self.lower.len {
+            let min = self.upper.first().expect("upper half is not empty");
+            self.upper.remove(min);
+            self.lower.add(min);
+        }
+    }
+
+    pub fn summary(&self) -> Option<Aggregate> {
+        let count = self.lower.len + self.upper.len;
+        let lower_max = self.lower.last()?.0;
+        let median = if count.is_multiple_of(2) {
+            (lower_max + self.upper.first()?.0) / 2.0
+        } else {
+            lower_max
+        };
+        Some(Aggregate {
+            count,
+            min: self.lower.first()?.0,
+            max: self.upper.last().map_or(lower_max, |max| max.0),
+            mean: (self.sum + self.compensation) / count as f64,
+            median,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_added_and_removed_values() {
+        let mut aggregates = Aggregates::new(f64::to_f64);
+        assert_eq!(aggregates.summary(), None);
+
+        for value in [3.0, -1.0, 4.0, 1.0, 5.0] {
+            aggregates.add(&value);
+        }
+        assert_eq!(
+            aggregates.summary(),
+            Some(Aggregate {
+                count: 5,
+                min: -1.0,
+                max: 5.0,
+                mean: 2.4,
+                median: 3.0,
+            })
+        );
+
+        aggregates.remove(&5.0);
+        aggregates.remove(&-1.0);
+        aggregates.add(&1.0);
+        assert_eq!(
+            aggregates.summary(),
+            Some(Aggregate {
+                count: 4,
+                min: 1.0,
+                max: 4.0,
+                mean: 2.25,
+                median: 2.0,
+            })
+        );
+    }
+    #[test]
+    fn ignores_nan_values() {
+        let mut aggregates = Aggregates::new(f64::to_f64);
+        aggregates.add(&1.0);
+        aggregates.add(&3.0);
+        aggregates.remove(&1.0);
+        aggregates.add(&f64::NAN);
+        aggregates.remove(&f64::NAN);
+        aggregates.add(&1.0);
+
+        assert_eq!(
+            aggregates.summary(),
+            Some(Aggregate {
+                count: 2,
+                min: 1.0,
+                max: 3.0,
+                mean: 2.0,
+                median: 2.0,
+            })
+        );
+    }
+
+    #[test]
+    fn keeps_the_sum_exact_over_many_changes() {
+        let mut aggregates = Aggregates::new(f64::to_f64);
+        aggregates.add(&0.1);
+        for n in 0..10_000 {
+            let value = 1e15 + n as f64 * 0.3;
+            aggregates.add(&value);
+            aggregates.remove(&value);
+        }
+
+        assert_eq!(aggregates.summary().unwrap().mean, 0.1);
+    }
+}
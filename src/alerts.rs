@@ -0,0 +1,436 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    hash::Hash,
+    time::{Duration, Instant},
+};
+
+/// What a [`Rule`] checks. Values are compared in the unit of
+/// [`Numeric`](crate::Numeric), so in degrees Celsius for temperatures.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Condition {
+    Above(f64),
+    Below(f64),
+    /// The value moved by more than `delta` within `within`.
+    ChangedBy {
+        delta: f64,
+        within: Duration,
+    },
+}
+
+/// An alerting rule, such as "Paris above 35 for 10 minutes".
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule<K> {
+    /// Identifies the rule in its alerts.
+    pub name: String,
+    /// The key the rule applies to, or every key if `None`.
+    pub key: Option<K>,
+    pub condition: Condition,
+    /// How long the condition has to hold before the alert fires.
+    pub for_duration: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlertState {
+    Fired,
+    Resolved,
+}
+
+/// A rule started or stopped firing for a key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Alert<K> {
+    pub rule: String,
+    pub key: K,
+    pub state: AlertState,
+    /// The value of the key at the time, `None` if it was deleted.
+    pub value: Option<f64>,
+}
+
+/// Progress of one rule for one key.
+#[derive(Debug, Default)]
+struct Tracking {
+    value: Option<f64>,
+    /// Values within the window of a [`Condition::ChangedBy`] rule.
+    window: Window,
+    /// Since when the condition holds.
+    holds_since: Option<Instant>,
+    firing: bool,
+    /// Entry in [`Deadlines`], if the outcome depends on time passing.
+    deadline: Option<(Instant, u64)>,
+}
+
+/// The values within the window of a [`Condition::ChangedBy`] rule, after the
+/// one that was current when the window started. Their minimum and maximum
+/// are kept in monotonic queues, so each value costs amortised `O(1)`.
+#[derive(Debug, Default)]
+struct Window {
+    /// When each value was applied, oldest first.
+    applied: VecDeque<Instant>,
+    /// Position of the oldest value among all values pushed.
+    first: u64,
+    /// Positions and values that may become the minimum, increasing.
+    mins: VecDeque<(u64, f64)>,
+    /// Positions and values that may become the maximum, decreasing.
+    maxs: VecDeque<(u64, f64)>,
+}
+
+impl Window {
+    fn push(&mut self, at: Instant, value: f64) {
+        let position = self.first + self.applied.len() as u64;
+        self.applied.push_back(at);
+        if value.is_nan() {
+            return;
+        }
+        while self.mins.back().is_some_and(|(_, min)| *min >= value) {
+            self.mins.pop_back();
+        }
+        self.mins.push_back((position, value));
+        while self.maxs.back().is_some_and(|(_, max)| *max <= value) {
+            self.maxs.pop_back();
+        }
+        self.maxs.push_back((position, value));
+    }
+
+    fn pop_front(&mut self) {
+        self.applied.pop_front();
+        if self
+            .mins
+            .front()
+            .is_some_and(|(position, _)| *position == self.first)
+        {
+            self.mins.pop_front();
+        }
+        if self
+            .maxs
+            .front()
+            .is_some_and(|(position, _)| *position == self.first)
+        {
+            self.maxs.pop_front();
+        }
+        self.first += 1;
+    }
+
+    /// When the value after the oldest one was applied, which ends the
+    /// oldest one's time in the window.
+    fn second(&self) -> Option<Instant> {
+        self.applied.get(1).copied()
+    }
+
+    /// Difference between the highest and the lowest value.
+    fn spread(&self) -> Option<f64> {
+        Some(self.maxs.front()?.1 - self.mins.front()?.1)
+    }
+}
+
+/// Evaluates rules against the values applied to the cache.
+pub(crate) struct Alerts<K> {
+    rules: Vec<Rule<K>>,
+    tracking: HashMap<(usize, K), Tracking>,
+    deadlines: Deadlines<K>,
+}
+
+/// Rules and keys ordered by when they have to be evaluated again, made
+/// unique by a counter.
+struct Deadlines<K> {
+    queue: BTreeMap<(Instant, u64), (usize, K)>,
+    next_id: u64,
+}
+
+impl<K: Clone> Deadlines<K> {
+    /// Replaces the deadline of `tracking`, the progress of `id`, with `at`.
+    fn set(&mut self, id: &(usize, K), tracking: &mut Tracking, at: Option<Instant>) {
+        if let Some(deadline) = tracking.deadline.take() {
+            self.queue.remove(&deadline);
+        }
+        if let Some(at) = at {
+            let deadline = (at, self.next_id);
+            self.next_id += 1;
+            self.queue.insert(deadline, id.clone());
+            tracking.deadline = Some(deadline);
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone> Alerts<K> {
+    pub fn new(rules: Vec<Rule<K>>) -> Self {
+        Self {
+            rules,
+            tracking: HashMap::new(),
+            deadlines: Deadlines {
+                queue: BTreeMap::new(),
+                next_id: 0,
+            },
+        }
+    }
+
+    /// Evaluates the rules of `key` after its value changed to `value`, or
+    /// it was deleted if `None`.
+    pub fn apply(&mut self, key: &K, value: Option<f64>, now: Instant) -> Vec<Alert<K>> {
+        let mut alerts = Vec::new();
+        for (index, rule) in self.rules.iter().enumerate() {
+            if rule.key.as_ref().is_some_and(|only| only != key) {
+                continue;
+            }
+            let id = (index, key.clone());
+            let tracking = self.tracking.entry(id.clone()).or_default();
+            tracking.value = value;
+            match value {
+                Some(value) if matches!(rule.condition, Condition::ChangedBy { .. }) => {
+                    tracking.window.push(now, value)
+                }
+                Some(_) => {}
+                None => tracking.window = Window::default(),
+            }
+            alerts.extend(evaluate(rule, key, tracking, now));
+            let forget = value.is_none() && !tracking.firing;
+            let at = if forget {
+                None
+            } else {
+                deadline(rule, tracking)
+            };
+            self.deadlines.set(&id, tracking, at);
+            if forget {
+                self.tracking.remove(&id);
+            }
+        }
+        alerts
+    }
+
+    /// Replaces all tracked values with `values`, for when changes were
+    /// missed.
+    pub fn sync(&mut self, values: HashMap<K, f64>, now: Instant) -> Vec<Alert<K>> {
+        let mut alerts = Vec::new();
+        let deleted: HashSet<K> = self
+            .tracking
+            .keys()
+            .map(|(_, key)| key.clone())
+            .filter(|key| !values.contains_key(key))
+            .collect();
+        for key in deleted {
+            alerts.extend(self.apply(&key, None, now));
+        }
+        for (key, value) in values {
+            alerts.extend(self.apply(&key, Some(value), now));
+        }
+        alerts
+    }
+
+    /// Re-evaluates the rules whose deadline has passed.
+    pub fn tick(&mut self, now: Instant) -> Vec<Alert<K>> {
+        let mut alerts = Vec::new();
+        while let Some(entry) = self.deadlines.queue.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            let id = entry.remove();
+            let tracking = self
+                .tracking
+                .get_mut(&id)
+                .expect("scheduled rule is tracked");
+            tracking.deadline = None;
+            let rule = &self.rules[id.0];
+            alerts.extend(evaluate(rule, &id.1, tracking, now));
+            // evaluating moved the deadline past `now`
+            self.deadlines.set(&id, tracking, deadline(rule, tracking));
+        }
+        alerts
+    }
+
+    /// When [`Alerts::tick`] should be called next, if at all.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.deadlines
+            .queue
+            .first_key_value()
+            .map(|((deadline, _), _)| *deadline)
+    }
+}
+
+/// When the outcome of `rule` for `tracking` changes unless a new value is
+/// applied first.
+fn deadline<K>(rule: &Rule<K>, tracking: &Tracking) -> Option<Instant> {
+    let fires = tracking
+        .holds_since
+        .filter(|_| !tracking.firing)
+        .map(|since| since + rule.for_duration);
+    // the oldest value drops out once the next one leaves the window
+    let window_moves = match rule.condition {
+        Condition::ChangedBy { within, .. } => tracking.window.second().map(|at| at + within),
+        _ => None,
+    };
+    fires.into_iter().chain(window_moves).min()
+}
+
+fn evaluate<K: Clone>(
+    rule: &Rule<K>,
+    key: &K,
+    tracking: &mut Tracking,
+    now: Instant,
+) -> Option<Alert<K>> {
+    let holds = match (rule.condition, tracking.value) {
+        (_, None) => false,
+        (Condition::Above(threshold), Some(value)) => value > threshold,
+        (Condition::Below(threshold), Some(value)) => value < threshold,
+        (Condition::ChangedBy { delta, within }, Some(_)) => {
+            // keep the value that was current when the window started
+            while tracking
+                .window
+                .second()
+                .is_some_and(|at| now.duration_since(at) >= within)
+            {
+                tracking.window.pop_front();
+            }
+            tracking
+                .window
+                .spread()
+                .is_some_and(|spread| spread > delta)
+        }
+    };
+
+    let state = if holds {
+        let since = *tracking.holds_since.get_or_insert(now);
+        if tracking.firing || now.duration_since(since) < rule.for_duration {
+            return None;
+        }
+        tracking.firing = true;
+        AlertState::Fired
+    } else {
+        tracking.holds_since = None;
+        if !tracking.firing {
+            return None;
+        }
+        tracking.firing = false;
+        AlertState::Resolved
+    };
+    Some(Alert {
+        rule: rule.name.clone(),
+        key: key.clone(),
+        state,
+        value: tracking.value,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn states(alerts: Vec<Alert<&str>>) -> Vec<(&str, AlertState)> {
+        alerts
+            .into_iter()
+            .map(|alert| (alert.key, alert.state))
+            .collect()
+    }
+
+    #[test]
+    fn fires_once_condition_held_long_enough() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut alerts = Alerts::new(vec![Rule {
+            name: "heat".to_string(),
+            key: Some("Paris"),
+            condition: Condition::Above(35.0),
+            for_duration: Duration::from_secs(600),
+        }]);
+
+        assert!(alerts.apply(&"Rome", Some(40.0), at(0)).is_empty());
+        assert!(alerts.apply(&"Paris", Some(36.0), at(0)).is_empty());
+        assert_eq!(alerts.next_deadline(), Some(at(600)));
+        assert!(alerts.tick(at(599)).is_empty());
+        assert_eq!(
+            states(alerts.tick(at(600))),
+            vec![("Paris", AlertState::Fired)]
+        );
+        assert_eq!(alerts.next_deadline(), None);
+
+        assert!(alerts.apply(&"Paris", Some(37.0), at(700)).is_empty());
+        assert_eq!(
+            states(alerts.apply(&"Paris", Some(30.0), at(800))),
+            vec![("Paris", AlertState::Resolved)]
+        );
+    }
+
+    #[test]
+    fn fires_on_change_within_window() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut alerts = Alerts::new(vec![Rule {
+            name: "swing".to_string(),
+            key: None,
+            condition: Condition::ChangedBy {
+                delta: 5.0,
+                within: Duration::from_secs(3600),
+            },
+            for_duration: Duration::ZERO,
+        }]);
+
+        alerts.apply(&"Oslo", Some(-2.0), at(0));
+        assert!(alerts.apply(&"Oslo", Some(2.0), at(1000)).is_empty());
+        assert_eq!(
+            states(alerts.apply(&"Oslo", Some(4.0), at(2000))),
+            vec![("Oslo", AlertState::Fired)]
+        );
+        // -2 is current until 1000, so it leaves the window at 4600
+        assert_eq!(alerts.next_deadline(), Some(at(4600)));
+        assert!(alerts.tick(at(4599)).is_empty());
+        assert_eq!(
+            states(alerts.tick(at(4600))),
+            vec![("Oslo", AlertState::Resolved)]
+        );
+
+        // an unchanged value is compared against, however old it is
+        assert!(alerts.apply(&"Rome", Some(0.0), at(5000)).is_empty());
+        assert!(alerts.tick(at(8600)).is_empty());
+        assert_eq!(
+            states(alerts.apply(&"Rome", Some(10.0), at(12200))),
+            vec![("Rome", AlertState::Fired)]
+        );
+
+        alerts.apply(&"Lima", Some(20.0), at(13000));
+        assert_eq!(
+            states(alerts.sync(HashMap::from([("Lima", 26.0), ("Rome", 10.0)]), at(13001))),
+            vec![("Lima", AlertState::Fired)]
+        );
+    }
+    #[test]
+    fn tracks_extremes_of_sliding_window() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut window = Window::default();
+        for (secs, value) in [(0, 3.0), (1, 5.0), (2, 1.0), (3, 4.0), (4, 2.0)] {
+            window.push(at(secs), value);
+        }
+        assert_eq!(window.spread(), Some(4.0));
+
+        let mut spreads = Vec::new();
+        while window.second().is_some() {
+            window.pop_front();
+            spreads.push(window.spread());
+        }
+        // 5, 1, 4, 2 then 1, 4, 2 then 4, 2 then 2
+        assert_eq!(spreads, vec![Some(4.0), Some(3.0), Some(2.0), Some(0.0)]);
+    }
+
+    #[test]
+    fn schedules_only_keys_waiting_to_fire() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut alerts = Alerts::new(vec![Rule {
+            name: "heat".to_string(),
+            key: None,
+            condition: Condition::Above(35.0),
+            for_duration: Duration::from_secs(600),
+        }]);
+
+        alerts.apply(&"Paris", Some(36.0), at(0));
+        alerts.apply(&"Rome", Some(37.0), at(100));
+        alerts.apply(&"Oslo", Some(10.0), at(100));
+        assert_eq!(alerts.deadlines.queue.len(), 2);
+        assert_eq!(alerts.next_deadline(), Some(at(600)));
+
+        alerts.apply(&"Paris", None, at(200));
+        assert_eq!(alerts.next_deadline(), Some(at(700)));
+        assert_eq!(
+            states(alerts.tick(at(700))),
+            vec![("Rome", AlertState::Fired)]
+        );
+        assert!(alerts.deadlines.queue.is_empty());
+    }
+}
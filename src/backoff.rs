@@ -0,0 +1,89 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+
+/// Exponential backoff with jitter between consecutive attempts.
+///
+/// The delay for attempt `n` (0-based) is `initial * multiplier^n`, capped at
+/// `max`, and then scaled down by a random factor in `[1 - jitter, 1]` so that
+/// many caches reconnecting to the same upstream do not do so in lockstep.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    pub multiplier: f64,
+    /// Fraction of the delay that is randomized, clamped to `0.0..=1.0`.
+    pub jitter: f64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(30),
+            multiplier: 2.0,
+            jitter: 0.2,
+        }
+    }
+}
+
+impl Backoff {
+    /// Delay before attempt `attempt`, without jitter applied.
+    pub fn base_delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.min(i32::MAX as u32) as i32;
+        let secs = self.initial.as_secs_f64() * self.multiplier.powi(exponent);
+        Duration::from_secs_f64(secs.min(self.max.as_secs_f64()).max(0.0))
+    }
+
+    /// Delay before attempt `attempt`, with jitter applied.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        self.base_delay(attempt)
+            .mul_f64(1.0 - jitter * random_unit())
+    }
+}
+
+/// Uniformly distributed value in `[0, 1)`, seeded from the std hasher keys
+/// so that we don't need a dependency on `rand` just for jitter.
+fn random_unit() -> f64 {
+    let bits = RandomState::new().build_hasher().finish();
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grows_exponentially_up_to_max() {
+        let backoff = Backoff {
+            initial: Duration::from_millis(10),
+            max: Duration::from_millis(50),
+            multiplier: 2.0,
+            jitter: 0.0,
+        };
+
+        assert_eq!(backoff.delay(0), Duration::from_millis(10));
+        assert_eq!(backoff.delay(1), Duration::from_millis(20));
+        assert_eq!(backoff.delay(2), Duration::from_millis(40));
+        assert_eq!(backoff.delay(3), Duration::from_millis(50));
+        assert_eq!(backoff.delay(u32::MAX), Duration::from_millis(50));
+    }
+
+    #[test]
+    fn jitter_stays_within_bounds() {
+        let backoff = Backoff {
+            jitter: 0.5,
+            ..Backoff::default()
+        };
+
+        for attempt in 0..20 {
+            let delay = backoff.delay(attempt);
+            let base = backoff.base_delay(attempt);
+            assert!(delay <= base);
+            assert!(delay >= base.mul_f64(0.5));
+        }
+    }
+}
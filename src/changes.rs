@@ -0,0 +1,187 @@
+use futures::stream::{self, BoxStream, StreamExt};
+use std::{collections::HashMap, hash::Hash, sync::Mutex};
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    watch,
+};
+
+/// Where an applied change came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeSource {
+    Fetch,
+    Subscribe,
+    /// The value outlived [`Config::ttl`](crate::Config::ttl) and was removed.
+    Expire,
+}
+
+/// A value that was applied to, or removed from, the cache.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change<K, V> {
+    pub key: K,
+    /// Value before the change, `None` if the key was not cached.
+    pub old: Option<V>,
+    /// Value after the change, `None` if the key was deleted.
+    pub new: Option<V>,
+    pub source: ChangeSource,
+}
+
+/// A watcher fell behind and `skipped` changes were dropped for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lagged {
+    pub skipped: u64,
+}
+
+/// Fans applied changes out to [`StreamCache::watch`](crate::StreamCache::watch)
+/// streams and to per-key watchers.
+pub(crate) struct Watchers<K, V> {
+    changes: broadcast::Sender<Change<K, V>>,
+    /// Only keys that are being watched have an entry, so notifying about an
+    /// unwatched key costs a single failed lookup.
+    keys: Mutex<HashMap<K, watch::Sender<Option<V>>>>,
+}
+
+impl<K, V> Watchers<K, V>
+where
+    K: Hash + Eq + Clone + Send + 'static,
+    V: Clone + Send + 'static,
+{
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            changes: broadcast::channel(capacity.max(1)).0,
+            keys: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn subscribe(&self) -> BoxStream<'static, Result<Change<K, V>, Lagged>> {
+        into_stream(self.changes.subscribe())
+    }
+
+    /// Watches `key`, whose value is currently `current`. Must be called with
+    /// the store's writer held so that no change slips in between.
+    pub fn subscribe_key(&self, key: K, current: Option<V>) -> watch::Receiver<Option<V>> {
+        let mut keys = self.keys.lock().expect("poisoned");
+        match keys.get(&key) {
+            Some(sender) => {
+                // The published value can only lag behind a value that
+                // outlived the TTL but was not removed yet.
+                let expired = sender.borrow().is_some() && current.is_none();
+                if expired {
+                    sender.send_replace(None);
+                }
+                sender.subscribe()
+            }
+            None => {
+                let (sender, receiver) = watch::channel(current);
+                keys.insert(key, sender);
+                receiver
+            }
+        }
+    }
+
+    /// Publishes an applied change to all watchers. Must be called with the
+    /// cache lock held, so watchers see changes in the order they were applied.
+    pub fn notify(&self, change: Option<Change<K, V>>) {
+        let Some(change) = change else {
+            return;
+        };
+
+        let mut keys = self.keys.lock().expect("poisoned");
+        if let Some(sender) = keys.get(&change.key) {
+            if sender.receiver_count() == 0 {
+                keys.remove(&change.key);
+            } else {
+                sender.send_replace(change.new.clone());
+            }
+        }
+        drop(keys);
+
+        // there may be no watchers, which is fine
+        let _ = self.changes.send(change);
+    }
+}
+
+/// Turns a broadcast receiver into a stream that yields [`Lagged`] in place
+/// of the changes it missed and ends once the sender is dropped.
+pub(crate) fn into_stream<K, V>(
+    receiver: broadcast::Receiver<Change<K, V>>,
+) -> BoxStream<'static, Result<Change<K, V>, Lagged>>
+where
+    K: Clone + Send + 'static,
+    V: Clone + Send + 'static,
+{
+    stream::unfold(receiver, |mut receiver| async move {
+        match receiver.recv().await {
+            Ok(change) => Some((Ok(change), receiver)),
+            Err(RecvError::Lagged(skipped)) => Some((Err(Lagged { skipped }), receiver)),
+            Err(RecvError::Closed) => None,
+        }
+    })
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn change(key: &'static str, new: u64) -> Change<&'static str, u64> {
+        Change {
+            key,
+            old: None,
+            new: Some(new),
+            source: ChangeSource::Subscribe,
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_lag_and_ends_when_closed() {
+        let (sender, receiver) = broadcast::channel(2);
+        let mut changes = into_stream(receiver);
+
+        for new in 0..5 {
+            sender.send(change("Berlin", new)).unwrap();
+        }
+        drop(sender);
+
+        assert_eq!(changes.next().await, Some(Err(Lagged { skipped: 3 })));
+        assert_eq!(changes.next().await, Some(Ok(change("Berlin", 3))));
+        assert_eq!(changes.next().await, Some(Ok(change("Berlin", 4))));
+        assert_eq!(changes.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn key_watcher_only_sees_its_key() {
+        let watchers = Watchers::new(16);
+        let mut berlin = watchers.subscribe_key("Berlin", Some(20));
+        assert_eq!(*berlin.borrow_and_update(), Some(20));
+
+        watchers.notify(Some(change("Paris", 25)));
+        assert!(!berlin.has_changed().unwrap());
+
+        watchers.notify(Some(change("Berlin", 21)));
+        assert!(berlin.has_changed().unwrap());
+        assert_eq!(*berlin.borrow_and_update(), Some(21));
+    }
+
+    #[tokio::test]
+    async fn new_key_watcher_sees_current_value() {
+        let watchers = Watchers::new(16);
+        let mut berlin = watchers.subscribe_key("Berlin", Some(20));
+        berlin.borrow_and_update();
+
+        // the value expired, but the deletion was not published yet
+        let again = watchers.subscribe_key("Berlin", None);
+
+        assert_eq!(*again.borrow(), None);
+        assert!(berlin.has_changed().unwrap());
+    }
+
+    #[tokio::test]
+    async fn forgets_keys_without_watchers() {
+        let watchers = Watchers::new(16);
+        drop(watchers.subscribe_key("Berlin", None));
+
+        watchers.notify(Some(change("Berlin", 21)));
+
+        assert!(watchers.keys.lock().unwrap().is_empty());
+    }
+}
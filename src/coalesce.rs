@@ -0,0 +1,186 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    hash::Hash,
+    time::{Duration, Instant},
+};
+
+use crate::{source::Update, store::supersedes, Version};
+
+/// A streamed update with the store's sequence number when it was received,
+/// see [`StoreWriter::merged_since`](crate::store::StoreWriter::merged_since).
+pub(crate) type Received<K, V> = (Update<K, V>, u64);
+
+/// Applies streamed updates at most once per interval per key. Updates that
+/// arrive sooner are held back, and only the latest one per key is applied
+/// once the interval has passed.
+pub(crate) struct Coalescer<K, V> {
+    /// Passes all updates through if `None`.
+    interval: Option<Duration>,
+    /// When each key was last applied, for keys applied within the interval.
+    applied: HashMap<K, Instant>,
+    /// Applied keys in the order they were applied, to forget them once the
+    /// interval has passed.
+    applications: VecDeque<(Instant, K)>,
+    pending: HashMap<K, (Option<V>, Option<Version>, u64)>,
+    /// Held back keys ordered by when they are due, made unique by a counter.
+    deadlines: BTreeMap<(Instant, u64), K>,
+    next_id: u64,
+}
+
+impl<K: Hash + Eq + Clone, V> Coalescer<K, V> {
+    pub fn new(interval: Option<Duration>) -> Self {
+        Self {
+            interval,
+            applied: HashMap::new(),
+            applications: VecDeque::new(),
+            pending: HashMap::new(),
+            deadlines: BTreeMap::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Returns the update if it is to be applied right away.
+    pub fn offer(&mut self, update: Received<K, V>, now: Instant) -> Option<Received<K, V>> {
+        let Some(interval) = self.interval else {
+            return Some(update);
+        };
+        self.forget(interval, now);
+        let ((key, value, version), received) = update;
+        let applied_at = self
+            .applied
+            .get(&key)
+            .copied()
+            .filter(|at| now.duration_since(*at) < interval);
+        let Some(applied_at) = applied_at else {
+            self.mark_applied(key.clone(), now);
+            return Some(((key, value, version), received));
+        };
+        match self.pending.get(&key) {
+            Some((_, current, _)) if !supersedes(version, *current) => {}
+            Some(_) => {
+                self.pending.insert(key, (value, version, received));
+            }
+            None => {
+                self.deadlines
+                    .insert((applied_at + interval, self.next_id), key.clone());
+                self.next_id += 1;
+                self.pending.insert(key, (value, version, received));
+            }
+        }
+        None
+    }
+
+    /// When the next held back update is due.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.deadlines
+            .first_key_value()
+            .map(|((deadline, _), _)| *deadline)
+    }
+
+    /// Takes the held back updates that are due.
+    pub fn due(&mut self, now: Instant) -> Vec<Received<K, V>> {
+        let Some(interval) = self.interval else {
+            return Vec::new();
+        };
+        let mut due = Vec::new();
+        while let Some(entry) = self.deadlines.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            let key = entry.remove();
+            let (value, version, received) = self.pending.remove(&key).expect("due key is pending");
+            self.mark_applied(key.clone(), now);
+            due.push(((key, value, version), received));
+        }
+        self.forget(interval, now);
+        due
+    }
+
+    /// Takes all held back updates, for when the stream ends.
+    pub fn drain(&mut self) -> Vec<Received<K, V>> {
+        self.deadlines.clear();
+        self.pending
+            .drain()
+            .map(|(key, (value, version, received))| ((key, value, version), received))
+            .collect()
+    }
+
+    fn mark_applied(&mut self, key: K, now: Instant) {
+        self.applied.insert(key.clone(), now);
+        self.applications.push_back((now, key));
+    }
+
+    /// Forgets keys that were not applied within the interval, unless they
+    /// have an update held back.
+    fn forget(&mut self, interval: Duration, now: Instant) {
+        while let Some((at, _)) = self.applications.front() {
+            if now.duration_since(*at) < interval {
+                break;
+            }
+            let (at, key) = self.applications.pop_front().expect("not empty");
+            if self.applied.get(&key) == Some(&at) && !self.pending.contains_key(&key) {
+                self.applied.remove(&key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn applies_latest_update_once_per_interval() {
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+        let mut coalescer = Coalescer::new(Some(Duration::from_millis(100)));
+
+        assert!(coalescer
+            .offer((("Oslo", Some(1), None), 0), at(0))
+            .is_some());
+        assert!(coalescer
+            .offer((("Oslo", Some(2), None), 2), at(10))
+            .is_none());
+        assert!(coalescer
+            .offer((("Oslo", Some(3), None), 3), at(20))
+            .is_none());
+        assert!(coalescer
+            .offer((("Rome", Some(9), None), 0), at(30))
+            .is_some());
+        assert_eq!(coalescer.next_deadline(), Some(at(100)));
+
+        assert!(coalescer.due(at(99)).is_empty());
+        assert_eq!(coalescer.due(at(100)), vec![(("Oslo", Some(3), None), 3)]);
+        assert_eq!(coalescer.next_deadline(), None);
+
+        assert!(coalescer
+            .offer((("Oslo", Some(4), None), 0), at(150))
+            .is_none());
+        assert_eq!(coalescer.drain(), vec![(("Oslo", Some(4), None), 0)]);
+        assert!(coalescer
+            .offer((("Rome", Some(8), None), 0), at(150))
+            .is_some());
+    }
+
+    #[test]
+    fn forgets_keys_applied_before_the_interval() {
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+        let mut coalescer = Coalescer::new(Some(Duration::from_millis(100)));
+
+        for n in 0..1000 {
+            assert!(coalescer
+                .offer(((n, Some(n), None), 0), at(n / 10))
+                .is_some());
+        }
+        assert!(coalescer.offer(((0, Some(1), None), 0), at(50)).is_none());
+        assert!(coalescer
+            .offer(((1000, Some(0), None), 0), at(200))
+            .is_some());
+
+        assert_eq!(coalescer.applied.len(), 2);
+        assert_eq!(coalescer.next_deadline(), Some(at(100)));
+        assert_eq!(coalescer.due(at(200)), vec![((0, Some(1), None), 0)]);
+        assert_eq!(coalescer.applied.len(), 2);
+    }
+}
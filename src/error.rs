@@ -0,0 +1,63 @@
+use std::fmt;
+
+/// Error returned by an [`Api`](crate::Api), classified so that the cache
+/// knows whether retrying can help.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApiError {
+    /// The request did not complete in time.
+    Timeout(String),
+    /// The upstream is temporarily unavailable.
+    Unavailable(String),
+    /// The credentials were rejected.
+    Unauthorized(String),
+    /// A response or record could not be decoded.
+    Malformed(String),
+}
+
+impl ApiError {
+    /// Whether the same request may succeed if it is retried.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, ApiError::Timeout(_) | ApiError::Unavailable(_))
+    }
+
+    /// Whether no further request can succeed, so the cache should stop
+    /// talking to the upstream. A malformed record is neither retryable nor
+    /// fatal: it is skipped and the stream continues.
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, ApiError::Unauthorized(_))
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::Timeout(message) => write!(f, "timeout: {}", message),
+            ApiError::Unavailable(message) => write!(f, "unavailable: {}", message),
+            ApiError::Unauthorized(message) => write!(f, "unauthorized: {}", message),
+            ApiError::Malformed(message) => write!(f, "malformed: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_errors() {
+        let timeout = ApiError::Timeout("no response after 5s".to_string());
+        assert!(timeout.is_retryable());
+        assert!(!timeout.is_fatal());
+
+        let unauthorized = ApiError::Unauthorized("token expired".to_string());
+        assert!(!unauthorized.is_retryable());
+        assert!(unauthorized.is_fatal());
+
+        let malformed = ApiError::Malformed("missing temperature".to_string());
+        assert!(!malformed.is_retryable());
+        assert!(!malformed.is_fatal());
+        assert_eq!(malformed.to_string(), "malformed: missing temperature");
+    }
+}
@@ -0,0 +1,120 @@
+use std::{fmt, time::Duration};
+
+use crate::{ApiError, ChangeSource};
+
+/// Which `fetch` an event refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FetchKind {
+    /// The fetch that populates the cache on startup.
+    Initial,
+    /// A periodic reconciliation, see [`Config::refresh_interval`](crate::Config::refresh_interval).
+    Refresh,
+    /// The fetch that reconciles the cache after the stream was resubscribed.
+    Resubscribe,
+}
+
+/// Something that happened in the background tasks of the cache.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    FetchStarted {
+        kind: FetchKind,
+        attempt: u32,
+    },
+    FetchSucceeded {
+        kind: FetchKind,
+        attempt: u32,
+        entries: usize,
+        elapsed: Duration,
+    },
+    FetchFailed {
+        kind: FetchKind,
+        attempt: u32,
+        error: ApiError,
+        elapsed: Duration,
+        /// Delay before the next attempt, `None` if there is none.
+        retry_in: Option<Duration>,
+    },
+    /// The `subscribe` stream delivered an error instead of an update.
+    StreamItemFailed {
+        error: ApiError,
+    },
+    /// The `subscribe` stream ended and will be resubscribed.
+    StreamEnded {
+        resubscribe_in: Duration,
+    },
+    /// The `subscribe` stream delivered a fatal error and was abandoned.
+    StreamStopped {
+        error: ApiError,
+    },
+    /// Values were written to the cache.
+    EntriesApplied {
+        source: ChangeSource,
+        count: usize,
+    },
+    /// A background task panicked, reported by [`StreamCache::shutdown`](crate::StreamCache::shutdown).
+    TaskPanicked {
+        message: String,
+    },
+}
+
+/// Receives the [`Event`]s of a cache, e.g. to forward them to a logging or
+/// tracing stack. Called from the background tasks, so implementations
+/// should return quickly.
+pub trait EventSink: Send + Sync + 'static {
+    fn record(&self, event: Event);
+}
+
+impl fmt::Debug for dyn EventSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EventSink")
+    }
+}
+
+/// Discards all events.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopSink;
+
+impl EventSink for NoopSink {
+    fn record(&self, _event: Event) {}
+}
+
+/// Prints failures and stream endings to stderr; the default sink.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StderrSink;
+
+impl EventSink for StderrSink {
+    fn record(&self, event: Event) {
+        match event {
+            Event::FetchFailed {
+                kind,
+                attempt,
+                error,
+                ..
+            } => {
+                let kind = match kind {
+                    FetchKind::Initial => "initial",
+                    FetchKind::Refresh => "refresh",
+                    FetchKind::Resubscribe => "resubscribe",
+                };
+                eprintln!(
+                    "Failed to perform {} fetch (attempt {}): {}",
+                    kind, attempt, error
+                )
+            }
+            Event::StreamItemFailed { error } => {
+                eprintln!("Failed to get update from subscribe: {}", error)
+            }
+            Event::StreamEnded { resubscribe_in } => eprintln!(
+                "Subscribe stream ended, resubscribing in {:?}",
+                resubscribe_in
+            ),
+            Event::StreamStopped { error } => {
+                eprintln!("Subscribe stream stopped after fatal error: {}", error)
+            }
+            Event::TaskPanicked { message } => eprintln!("Background task panicked: {}", message),
+            Event::FetchStarted { .. }
+            | Event::FetchSucceeded { .. }
+            | Event::EntriesApplied { .. } => {}
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// How much of each key's history [`StreamCache::history`](crate::StreamCache::history)
+/// keeps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryLimit {
+    /// The last `n` values.
+    Len(usize),
+    /// The values applied within this duration, and the one before them,
+    /// which was still current when the duration started.
+    Age(Duration),
+}
+
+/// A value of a key and when it was applied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sample<V> {
+    pub value: V,
+    pub at: Instant,
+    /// Wall-clock time the value was applied, for display and logging.
+    pub wall_time: SystemTime,
+}
+
+/// Bounded ring buffer of the values applied to one key, oldest first.
+#[derive(Debug)]
+pub(crate) struct History<V> {
+    samples: VecDeque<Sample<V>>,
+}
+
+impl<V> Default for History<V> {
+    fn default() -> Self {
+        Self {
+            samples: VecDeque::new(),
+        }
+    }
+}
+
+impl<V: Clone> History<V> {
+    pub fn push(&mut self, sample: Sample<V>, limit: HistoryLimit) {
+        self.samples.push_back(sample);
+        match limit {
+            HistoryLimit::Len(len) => {
+                while self.samples.len() > len {
+                    self.samples.pop_front();
+                }
+            }
+            HistoryLimit::Age(_) => {
+                let expired = self.expired(limit);
+                self.samples.drain(..expired);
+            }
+        }
+    }
+
+    /// Number of leading samples outside `limit`. The newest sample is never
+    /// outside, as it is current until the next one is applied.
+    fn expired(&self, limit: HistoryLimit) -> usize {
+        match limit {
+            HistoryLimit::Len(_) => 0,
+            HistoryLimit::Age(age) => self
+                .samples
+                .partition_point(|sample| sample.at.elapsed() > age)
+                .saturating_sub(1),
+        }
+    }
+
+    /// The samples within `limit`. Samples are only dropped on `push`, so
+    /// ones that have aged out since are skipped here.
+    pub fn samples(&self, limit: HistoryLimit) -> Vec<Sample<V>> {
+        self.samples
+            .iter()
+            .skip(self.expired(limit))
+            .cloned()
+            .collect()
+    }
+
+    /// The value that was current at `at`, if it is still in the history.
+    pub fn value_at(&self, at: Instant, limit: HistoryLimit) -> Option<V> {
+        let retained = self.expired(limit);
+        let applied = self.samples.partition_point(|sample| sample.at <= at);
+        // the value before the oldest retained sample is unknown
+        (applied > retained).then(|| self.samples[applied - 1].value.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(value: u64, at: Instant) -> Sample<u64> {
+        Sample {
+            value,
+            at,
+            wall_time: SystemTime::now(),
+        }
+    }
+
+    #[test]
+    fn keeps_the_last_values() {
+        let start = Instant::now();
+        let limit = HistoryLimit::Len(2);
+        let mut history = History::default();
+        for (value, offset) in [(20, 0), (21, 10), (22, 20)] {
+            history.push(sample(value, start + Duration::from_secs(offset)), limit);
+        }
+
+        let values: Vec<_> = history
+            .samples(limit)
+            .into_iter()
+            .map(|sample| sample.value)
+            .collect();
+        assert_eq!(values, vec![21, 22]);
+        assert_eq!(history.value_at(start, limit), None);
+        assert_eq!(
+            history.value_at(start + Duration::from_secs(15), limit),
+            Some(21)
+        );
+        assert_eq!(
+            history.value_at(start + Duration::from_secs(60), limit),
+            Some(22)
+        );
+    }
+
+    #[test]
+    fn drops_values_older_than_limit() {
+        let limit = HistoryLimit::Age(Duration::from_millis(20));
+        let mut history = History::default();
+        let start = Instant::now();
+        history.push(sample(19, start), limit);
+        history.push(sample(20, start + Duration::from_millis(1)), limit);
+        std::thread::sleep(Duration::from_millis(30));
+
+        // the last value is kept, as it is still current
+        let values: Vec<_> = history
+            .samples(limit)
+            .into_iter()
+            .map(|sample| sample.value)
+            .collect();
+        assert_eq!(values, vec![20]);
+        assert_eq!(history.value_at(Instant::now(), limit), Some(20));
+        assert_eq!(history.value_at(start, limit), None);
+
+        history.push(sample(21, Instant::now()), limit);
+        assert_eq!(history.samples.len(), 2);
+        assert_eq!(history.value_at(Instant::now(), limit), Some(21));
+    }
+}
@@ -0,0 +1,117 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+    ops::{Bound, RangeBounds},
+};
+
+use crate::aggregate::Ordered;
+
+/// Keys ordered by their value, for range and top-N queries. Like in
+/// [`Aggregates`](crate::aggregate::Aggregates), values that are NaN or
+/// infinite are left out.
+#[derive(Debug)]
+pub(crate) struct Index<K, V> {
+    measure: fn(&V) -> f64,
+    values: BTreeMap<Ordered, HashMap<K, V>>,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> Index<K, V> {
+    pub fn new(measure: fn(&V) -> f64) -> Self {
+        Self {
+            measure,
+            values: BTreeMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, key: &K, value: &V) {
+        let measured = (self.measure)(value);
+        if !measured.is_finite() {
+            return;
+        }
+        let keys = self.values.entry(Ordered(measured));
+        keys.or_default().insert(key.clone(), value.clone());
+    }
+
+    pub fn remove(&mut self, key: &K, value: &V) {
+        let value = Ordered((self.measure)(value));
+        if !value.0.is_finite() {
+            return;
+        }
+        if let Some(keys) = self.values.get_mut(&value) {
+            keys.remove(key);
+            if keys.is_empty() {
+                self.values.remove(&value);
+            }
+        }
+    }
+
+    /// Keys with a value in `range`, ordered by value.
+    pub fn range(&self, range: impl RangeBounds<V>) -> Vec<(K, V)> {
+        let bound = |bound: Bound<&V>| bound.map(|value| Ordered((self.measure)(value)));
+        let (start, end) = (bound(range.start_bound()), bound(range.end_bound()));
+        // `BTreeMap::range` panics on these, they are just empty here
+        let empty = match (start, end) {
+            (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+            (Bound::Included(start) | Bound::Excluded(start), Bound::Included(end))
+            | (Bound::Included(start), Bound::Excluded(end)) => start > end,
+            _ => false,
+        };
+        if empty {
+            return Vec::new();
+        }
+        self.values
+            .range((start, end))
+            .flat_map(|(_, keys)| keys.iter())
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+
+    /// The `n` keys with the highest values, highest first.
+    pub fn top_n(&self, n: usize) -> Vec<(K, V)> {
+        self.values
+            .values()
+            .rev()
+            .flat_map(|keys| keys.iter())
+            .take(n)
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Numeric;
+
+    use super::*;
+
+    #[test]
+    fn queries_keys_by_value() {
+        let mut index = Index::new(u64::to_f64);
+        for (key, value) in [("Oslo", 12), ("Rome", 27), ("Cairo", 35), ("Lima", 19)] {
+            index.add(&key, &value);
+        }
+        index.remove(&"Lima", &19);
+        index.add(&"Lima", &26);
+
+        assert_eq!(index.range(20..30), vec![("Lima", 26), ("Rome", 27)]);
+        assert_eq!(index.range(..=12), vec![("Oslo", 12)]);
+        assert_eq!(
+            index.range((Bound::Excluded(26), Bound::Excluded(26))),
+            vec![]
+        );
+        assert_eq!(index.top_n(2), vec![("Cairo", 35), ("Rome", 27)]);
+        assert_eq!(index.top_n(10).len(), 4);
+    }
+
+    #[test]
+    fn ignores_non_finite_values() {
+        let mut index = Index::new(f64::to_f64);
+        for (key, value) in [("Oslo", 12.0), ("Rome", f64::NAN), ("Cairo", f64::INFINITY)] {
+            index.add(&key, &value);
+        }
+        index.remove(&"Rome", &f64::NAN);
+
+        assert_eq!(index.top_n(1), vec![("Oslo", 12.0)]);
+        assert_eq!(index.range(..), vec![("Oslo", 12.0)]);
+    }
+}
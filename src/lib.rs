@@ -1,92 +1,851 @@
+mod aggregate;
+mod alerts;
+mod backoff;
+mod changes;
+mod coalesce;
+mod error;
+mod events;
+mod history;
+mod index;
+mod metrics;
+mod retry;
+mod snapshot;
+mod source;
+mod status;
+mod store;
+mod temperature;
+
 use async_trait::async_trait;
 use futures::stream::BoxStream;
+use futures::Future;
+use futures::FutureExt;
 use futures::StreamExt;
 use std::{
+    borrow::Borrow,
     collections::HashMap,
+    hash::Hash,
+    ops::RangeBounds,
     result::Result,
     sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{mpsc, watch},
+    task::JoinHandle,
+    time,
 };
-use tokio::spawn;
 
-type City = String;
-type Temperature = u64;
+pub use aggregate::{Aggregate, Numeric};
+use alerts::Alerts;
+pub use alerts::{Alert, AlertState, Condition, Rule};
+pub use backoff::Backoff;
+use changes::Watchers;
+pub use changes::{Change, ChangeSource, Lagged};
+use coalesce::{Coalescer, Received};
+pub use error::ApiError;
+pub use events::{Event, EventSink, FetchKind, NoopSink, StderrSink};
+pub use history::{HistoryLimit, Sample};
+use metrics::Tee;
+pub use metrics::{Histogram, Metrics};
+pub use retry::RetryPolicy;
+pub use snapshot::CacheSnapshot;
+use source::{Snapshot, Source, Unversioned, Versioned};
+pub use status::{FetchOutcome, Status, SubscriptionState};
+pub use store::Entry;
+use store::{supersedes, Store};
+pub use temperature::{Temperature, Unit};
+
+pub type City = String;
+
+/// A change delivered by the `subscribe` stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Delta<K = City, V = Temperature> {
+    /// The key was added or its value changed.
+    Upsert(K, V),
+    /// Upstream stopped tracking the key.
+    Delete(K),
+}
+
+/// Upstream that the cache mirrors: a full `fetch` of all keys and a
+/// `subscribe` stream of changes. Defaults to the temperature API.
+///
+/// Keys missing from a `fetch` are treated as deleted.
+#[async_trait]
+pub trait Api<K = City, V = Temperature>: Send + Sync + 'static {
+    async fn fetch(&self) -> Result<HashMap<K, V>, ApiError>;
+    async fn subscribe(&self) -> BoxStream<Result<Delta<K, V>, ApiError>>;
+}
+
+/// Monotonic version of an upstream value, such as a sequence number or a
+/// source timestamp. A higher version is newer.
+pub type Version = u64;
 
+/// Variant of [`Api`] whose snapshot entries and updates carry a [`Version`],
+/// which lets the cache resolve races between `fetch` and `subscribe` by
+/// keeping the value with the highest version (last writer wins).
+///
+/// Deleted keys are remembered with their version until the next `fetch` is
+/// merged, so that older updates arriving late cannot bring them back.
+/// Without a [`Config::refresh_interval`] that only happens on resubscribe,
+/// so caches of keys that are deleted often should set one.
 #[async_trait]
-pub trait Api: Send + Sync + 'static {
-    async fn fetch(&self) -> Result<HashMap<City, Temperature>, String>;
-    async fn subscribe(&self) -> BoxStream<Result<(City, Temperature), String>>;
+pub trait VersionedApi<K = City, V = Temperature>: Send + Sync + 'static {
+    async fn fetch(&self) -> Result<HashMap<K, (V, Version)>, ApiError>;
+    async fn subscribe(&self) -> BoxStream<Result<(Delta<K, V>, Version), ApiError>>;
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Delay between resubscribe attempts after the `subscribe` stream ends.
+    /// It only starts over at `initial` once a subscription stayed up for at
+    /// least `max`, so an upstream that keeps closing the stream right away
+    /// is not hammered with resubscribes and fetches.
+    pub resubscribe_backoff: Backoff,
+    /// Retries applied to the initial `fetch`.
+    pub fetch_retry: RetryPolicy,
+    /// Interval of the periodic full `fetch` that reconciles the cache with
+    /// upstream, repairing values for which a streamed update was dropped.
+    /// Disabled when `None`.
+    pub refresh_interval: Option<Duration>,
+    /// Number of changes buffered for each [`StreamCache::watch`] stream
+    /// before the oldest are dropped for a watcher that falls behind. At
+    /// least one change is always buffered, so `0` counts as `1`.
+    pub watch_capacity: usize,
+    /// Entries that were not updated by `fetch` or `subscribe` for this long
+    /// are expired and read as absent. Disabled when `None`.
+    pub ttl: Option<Duration>,
+    /// Streamed updates of a key are applied at most once per this interval.
+    /// Updates arriving sooner are held back and only the latest one is
+    /// applied when the interval has passed, so update storms don't flood
+    /// watchers. A held back update is dropped if a `fetch` issued after it
+    /// arrived is applied first. Disabled when `None`.
+    pub coalesce_interval: Option<Duration>,
+    /// Past values kept per key for [`StreamCache::history`]. Disabled when
+    /// `None`.
+    pub history: Option<HistoryLimit>,
+    /// Receives the events of the background tasks.
+    pub event_sink: Arc<dyn EventSink>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            resubscribe_backoff: Backoff::default(),
+            fetch_retry: RetryPolicy::default(),
+            refresh_interval: None,
+            watch_capacity: 1024,
+            ttl: None,
+            coalesce_interval: None,
+            history: None,
+            event_sink: Arc::new(StderrSink),
+        }
+    }
 }
 
-pub struct StreamCache {
-    results: Arc<Mutex<HashMap<String, u64>>>,
+pub struct StreamCache<K = City, V = Temperature> {
+    results: Arc<Store<K, V>>,
+    status: Arc<Mutex<Status>>,
+    /// Set once the initial snapshot has been applied.
+    ready: Arc<watch::Sender<bool>>,
+    watchers: Arc<Watchers<K, V>>,
+    metrics: Arc<Metrics>,
+    /// Records events into `metrics` and the configured sink.
+    sink: Arc<dyn EventSink>,
+    config: Config,
+    /// Background tasks, aborted when the cache is dropped.
+    tasks: Mutex<Vec<JoinHandle<()>>>,
 }
 
-impl StreamCache {
-    pub fn new(api: impl Api) -> Self {
+/// The cache of city temperatures.
+pub type TemperatureCache = StreamCache<City, Temperature>;
+
+impl<K, V> StreamCache<K, V>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + PartialEq + Send + Sync + 'static,
+{
+    pub fn new(api: impl Api<K, V>) -> Self {
+        Self::with_config(api, Config::default())
+    }
+
+    pub fn with_config(api: impl Api<K, V>, config: Config) -> Self {
+        let instance = Self::empty(config);
+        instance.update_in_background(api);
+        instance
+    }
+
+    pub fn versioned(api: impl VersionedApi<K, V>) -> Self {
+        Self::versioned_with_config(api, Config::default())
+    }
+
+    pub fn versioned_with_config(api: impl VersionedApi<K, V>, config: Config) -> Self {
+        let instance = Self::empty(config);
+        instance.update_versioned_in_background(api);
+        instance
+    }
+
+    fn empty(config: Config) -> Self {
+        let metrics = Arc::new(Metrics::default());
         let instance = Self {
-            results: Arc::new(Mutex::new(HashMap::new())),
+            results: Arc::new(Store::new(config.ttl, config.history)),
+            status: Arc::new(Mutex::new(Status::default())),
+            ready: Arc::new(watch::Sender::new(false)),
+            watchers: Arc::new(Watchers::new(config.watch_capacity)),
+            sink: Arc::new(Tee {
+                metrics: metrics.clone(),
+                sink: config.event_sink.clone(),
+            }),
+            metrics,
+            config,
+            tasks: Mutex::new(Vec::new()),
         };
-        instance.update_in_background(api);
+        if let Some(ttl) = instance.config.ttl {
+            instance.expire_in_background(ttl);
+        }
         instance
     }
 
-    pub fn get(&self, key: &str) -> Option<u64> {
-        let results = self.results.lock().expect("poisoned");
-        results.get(key).copied()
+    /// Stops all background tasks and waits until they have finished, which
+    /// releases the `Api`.
+    ///
+    /// The tasks never hold the cache lock across an await point, so
+    /// cancelling them cannot leave a partially applied update behind.
+    pub async fn shutdown(self) {
+        let tasks = std::mem::take(&mut *self.tasks.lock().expect("poisoned"));
+        for task in &tasks {
+            task.abort();
+        }
+        for task in tasks {
+            if let Err(e) = task.await {
+                if e.is_panic() {
+                    self.sink.record(Event::TaskPanicked {
+                        message: e.to_string(),
+                    });
+                }
+            }
+        }
+    }
+
+    fn spawn(&self, task: impl Future<Output = ()> + Send + 'static) {
+        let handle = tokio::spawn(task);
+        let mut tasks = self.tasks.lock().expect("poisoned");
+        // forget tasks that have finished, such as those of dropped alert streams
+        tasks.retain_mut(|task| {
+            if !task.is_finished() {
+                return true;
+            }
+            if let Some(Err(e)) = task.now_or_never() {
+                if e.is_panic() {
+                    self.sink.record(Event::TaskPanicked {
+                        message: e.to_string(),
+                    });
+                }
+            }
+            false
+        });
+        tasks.push(handle);
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.results.get(key)
+    }
+
+    /// The cached value for `key`, unless it was last updated more than
+    /// `max_age` ago.
+    pub fn get_fresh<Q>(&self, key: &Q, max_age: Duration) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.get_entry(key)
+            .filter(|entry| entry.age() <= max_age)
+            .map(|entry| entry.value)
+    }
+
+    /// The cached value for `key` with metadata about its last update.
+    pub fn get_entry<Q>(&self, key: &Q) -> Option<Entry<V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.results.get_entry(key)
+    }
+
+    /// Values applied to `key` within [`Config::history`], oldest first.
+    /// Empty if history is disabled or the key is not cached.
+    pub fn history<Q>(&self, key: &Q) -> Vec<Sample<V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.results.history(key)
     }
 
-    fn fetch_in_background(&self, api_arc: &Arc<impl Api>) {
+    /// The value `key` had at `at`, or `None` if that is older than its
+    /// history.
+    pub fn value_at<Q>(&self, key: &Q, at: Instant) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.results.value_at(key, at)
+    }
+
+    /// Count, min, max, mean and median of all cached values. These are kept
+    /// up to date as changes are applied once this was first called, so only
+    /// the first call scans the cache. Values that are NaN or infinite are
+    /// left out.
+    pub fn aggregate(&self) -> Option<Aggregate>
+    where
+        V: Numeric,
+    {
+        self.results.aggregate()
+    }
+
+    /// Keys with a value in `range`, such as all cities between 25 and
+    /// 30 °C, ordered by value. Like [`StreamCache::aggregate`], the index
+    /// behind this is only maintained once it was first queried, and leaves
+    /// out values that are NaN or infinite.
+    pub fn range(&self, range: impl RangeBounds<V>) -> Vec<(K, V)>
+    where
+        V: Numeric,
+    {
+        self.results.range(range)
+    }
+
+    /// The `n` keys with the highest values, highest first.
+    pub fn top_n(&self, n: usize) -> Vec<(K, V)>
+    where
+        V: Numeric,
+    {
+        self.results.top_n(n)
+    }
+
+    /// Consistent view of all cached values, unaffected by later updates.
+    pub fn snapshot(&self) -> CacheSnapshot<K, V> {
+        CacheSnapshot::new(self.results.snapshot())
+    }
+
+    /// Number of times the background task has resubscribed after the
+    /// `subscribe` stream ended.
+    pub fn reconnect_count(&self) -> u64 {
+        self.status.lock().expect("poisoned").reconnects
+    }
+
+    /// Outcome of the initial `fetch`, including retries, or of the latest
+    /// successful `fetch` since then. Failures of later fetches are only
+    /// counted in [`Status::fetch_errors`].
+    pub fn fetch_outcome(&self) -> FetchOutcome {
+        self.status.lock().expect("poisoned").fetch.clone()
+    }
+
+    /// Health of the background tasks, e.g. for a `/health` endpoint.
+    pub fn status(&self) -> Status {
+        self.status.lock().expect("poisoned").clone()
+    }
+
+    /// Counters and histograms collected from the background tasks.
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Renders the metrics of the cache in the Prometheus text exposition
+    /// format, ready to be served from a `/metrics` endpoint.
+    pub fn render_metrics(&self) -> String {
+        let keys = self.results.len();
+        let status = self.status();
+        self.metrics.render(keys, &status)
+    }
+
+    /// Whether a `fetch` has been applied to the cache.
+    pub fn is_ready(&self) -> bool {
+        *watch::Sender::borrow(&self.ready)
+    }
+
+    /// Resolves once the initial `fetch` has been applied to the cache.
+    ///
+    /// If the retry policy gives up on the initial `fetch`, this only resolves
+    /// once a later refresh succeeds, so callers that cannot wait forever
+    /// should use [`StreamCache::wait_until_initialized`] instead.
+    pub async fn ready(&self) {
+        let mut ready = self.ready.subscribe();
+        // the sender lives as long as `self`, so this cannot fail
+        let _ = ready.wait_for(|ready| *ready).await;
+    }
+
+    /// Waits up to `timeout` for the initial `fetch` to be applied and
+    /// returns whether the cache is ready.
+    pub async fn wait_until_initialized(&self, timeout: Duration) -> bool {
+        time::timeout(timeout, self.ready()).await.is_ok()
+    }
+
+    /// Stream of all changes applied to the cache from now on.
+    ///
+    /// Each watcher buffers up to [`Config::watch_capacity`] changes. A
+    /// watcher that falls further behind loses the oldest ones and receives a
+    /// single [`Lagged`] with their count in their place, after which it
+    /// continues with the oldest change still buffered. The stream ends when
+    /// the cache is dropped.
+    pub fn watch(&self) -> BoxStream<'static, Result<Change<K, V>, Lagged>> {
+        self.watchers.subscribe()
+    }
+
+    /// Evaluates `rules` as changes are applied and streams an [`Alert`] each
+    /// time one of them starts or stops firing for a key. Values cached
+    /// before the call count as just applied. The stream ends when the cache
+    /// is dropped.
+    ///
+    /// Up to [`Config::watch_capacity`] alerts are buffered; evaluation waits
+    /// for a consumer that falls further behind.
+    pub fn alerts(&self, rules: Vec<Rule<K>>) -> BoxStream<'static, Alert<K>>
+    where
+        V: Numeric,
+    {
+        let (sender, receiver) = mpsc::channel(self.config.watch_capacity.max(1));
         let results = self.results.clone();
+        let mut changes = self.watchers.subscribe();
+        let mut alerts = Alerts::new(rules);
+
+        self.spawn(async move {
+            let values = || {
+                let snapshot = results.snapshot().into_iter();
+                snapshot
+                    .map(|(key, entry)| (key, entry.value.to_f64()))
+                    .collect()
+            };
+            // changes applied meanwhile are evaluated again, which is harmless
+            let mut fired = alerts.sync(values(), Instant::now());
+            loop {
+                for alert in fired {
+                    // a slow consumer holds off evaluation, and changes missed
+                    // meanwhile are caught up with through `Lagged`
+                    if sender.send(alert).await.is_err() {
+                        // nobody listens anymore
+                        return;
+                    }
+                }
+                let deadline = alerts.next_deadline();
+                let wake_at = time::Instant::from_std(deadline.unwrap_or_else(Instant::now));
+                fired = tokio::select! {
+                    change = changes.next() => match change {
+                        Some(Ok(change)) => {
+                            let value = change.new.as_ref().map(Numeric::to_f64);
+                            alerts.apply(&change.key, value, Instant::now())
+                        }
+                        Some(Err(Lagged { .. })) => alerts.sync(values(), Instant::now()),
+                        None => return,
+                    },
+                    _ = time::sleep_until(wake_at), if deadline.is_some() => {
+                        alerts.tick(Instant::now())
+                    }
+                    _ = sender.closed() => return,
+                };
+            }
+        });
+
+        futures::stream::unfold(receiver, |mut receiver| async move {
+            let alert = receiver.recv().await?;
+            Some((alert, receiver))
+        })
+        .boxed()
+    }
+
+    /// Watches the value of a single key, which is `None` while the key is not
+    /// cached. The receiver only wakes up for changes of this key, and only
+    /// ever holds its latest value.
+    pub fn watch_key(&self, key: impl Into<K>) -> watch::Receiver<Option<V>> {
+        let key = key.into();
+        // Hold off writers so that no change is published between reading
+        // the current value and registering the watcher.
+        let cache = self.results.write();
+        let current = cache.get(&key);
+        self.watchers.subscribe_key(key, current)
+    }
+
+    /// Keeps the cache in sync with upstream using the snapshot-plus-deltas
+    /// protocol: the subscription is opened first and its updates are buffered
+    /// while the snapshot is fetched. The snapshot is then applied and the
+    /// buffered updates replayed on top of it, as they are at least as recent
+    /// as the snapshot (or, for a [`VersionedApi`], discarded if they are
+    /// older). From then on streamed updates are applied directly.
+    ///
+    /// When the stream ends, the whole protocol runs again after resubscribing,
+    /// so that updates missed in between are reconciled by the new snapshot.
+    fn sync_in_background(&self, api_arc: &Arc<impl Source<K, V>>) {
+        let results = self.results.clone();
+        let status = self.status.clone();
+        let ready = self.ready.clone();
+        let watchers = self.watchers.clone();
+        let policy = self.config.fetch_retry.clone();
+        let backoff = self.config.resubscribe_backoff.clone();
+        let coalesce_interval = self.config.coalesce_interval;
+        let sink = self.sink.clone();
         let api = Arc::clone(api_arc);
 
-        spawn(async move {
-            // Step 1: Initial fetch to populate the cache
-            match api.fetch().await {
-                Ok(initial_data) => {
-                    let mut cache = results.lock().expect("poisoned");
-                    for (city, temperature) in initial_data {
-                        // cache.insert(city, temperature);
-                        cache.entry(city).or_insert(temperature); // prioritize 'subscribe'
+        self.spawn(async move {
+            let apply = |batch: Vec<Received<K, V>>| {
+                let mut cache = results.write();
+                let mut count = 0;
+                for (update, received) in batch {
+                    if cache.merged_since(received) {
+                        // held back while a newer snapshot was merged
+                        continue;
+                    }
+                    let change = cache.insert(update, ChangeSource::Subscribe);
+                    count += usize::from(change.is_some());
+                    watchers.notify(change);
+                }
+                if count > 0 {
+                    status.lock().expect("poisoned").last_update = Some(Instant::now());
+                    sink.record(Event::EntriesApplied {
+                        source: ChangeSource::Subscribe,
+                        count,
+                    });
+                }
+            };
+            let mut coalescer = Coalescer::new(coalesce_interval);
+            let mut kind = FetchKind::Initial;
+            let mut attempt = 0;
+            loop {
+                // Step 1: Subscribe first, so no update is missed while fetching
+                let mut updates = api.subscribe().await.fuse();
+                let subscribed_at = Instant::now();
+                status.lock().expect("poisoned").subscription = SubscriptionState::Streaming;
+                let mut ended = false;
+                let mut failed = false;
+
+                // Step 2: Fetch a snapshot, buffering streamed updates. Only
+                // the latest update per key matters, which bounds the buffer.
+                let mut buffered = HashMap::new();
+                let issued_at = results.write().issue_fetch();
+                let fetch = fetch_with_retry(&*api, kind, &policy, &status, &*sink);
+                tokio::pin!(fetch);
+                let fetched = loop {
+                    tokio::select! {
+                        fetched = &mut fetch => break fetched,
+                        update = updates.next(), if !ended => match update {
+                            Some(Ok((key, value, version))) => {
+                                let current = buffered.get(&key).and_then(|(_, version)| *version);
+                                if supersedes(version, current) {
+                                    buffered.insert(key, (value, version));
+                                }
+                            }
+                            Some(Err(e)) => {
+                                status.lock().expect("poisoned").record_stream_error(&e);
+                                sink.record(Event::StreamItemFailed { error: e.clone() });
+                                if e.is_fatal() {
+                                    ended = true;
+                                    failed = true;
+                                }
+                            }
+                            None => ended = true,
+                        },
+                    }
+                };
+
+                // Step 3: Merge the snapshot, dropping keys that are gone
+                // upstream, then replay the buffered updates
+                let (attempts, fetched_count, buffered_count) = {
+                    let mut cache = results.write();
+                    let mut fetched_count = 0;
+                    let attempts = match fetched {
+                        Some((snapshot, attempts)) => {
+                            let changes = cache.merge_snapshot(snapshot, issued_at);
+                            fetched_count = changes.len();
+                            for change in changes {
+                                watchers.notify(Some(change));
+                            }
+                            Some(attempts)
+                        }
+                        None => {
+                            cache.end_fetch(issued_at);
+                            None
+                        }
+                    };
+                    let mut buffered_count = 0;
+                    for (key, (value, version)) in buffered {
+                        let change = cache.insert((key, value, version), ChangeSource::Subscribe);
+                        buffered_count += usize::from(change.is_some());
+                        watchers.notify(change);
                     }
+                    (attempts, fetched_count, buffered_count)
+                };
+                sink.record(Event::EntriesApplied {
+                    source: ChangeSource::Fetch,
+                    count: fetched_count,
+                });
+                if buffered_count > 0 {
+                    sink.record(Event::EntriesApplied {
+                        source: ChangeSource::Subscribe,
+                        count: buffered_count,
+                    });
                 }
-                Err(e) => {
-                    eprintln!("Failed to perform initial fetch: {}", e);
+                if let Some(attempts) = attempts {
+                    let mut status = status.lock().expect("poisoned");
+                    status.fetch = FetchOutcome::Succeeded { attempts };
+                    status.last_update = Some(Instant::now());
+                    ready.send_replace(true);
                 }
+                if failed {
+                    let mut status = status.lock().expect("poisoned");
+                    status.subscription = SubscriptionState::Failed;
+                    if let Some(error) = status.last_stream_error.clone() {
+                        sink.record(Event::StreamStopped { error });
+                    }
+                    return;
+                }
+
+                // Step 4: Apply real-time updates until the stream ends
+                loop {
+                    let deadline = coalescer.next_deadline();
+                    let wake_at = time::Instant::from_std(deadline.unwrap_or_else(Instant::now));
+                    let update = tokio::select! {
+                        update = updates.next() => update,
+                        _ = time::sleep_until(wake_at), if deadline.is_some() => {
+                            apply(coalescer.due(Instant::now()));
+                            continue;
+                        }
+                    };
+                    match update {
+                        Some(Ok(update)) => {
+                            let received = results.seq();
+                            apply(
+                                coalescer
+                                    .offer((update, received), Instant::now())
+                                    .into_iter()
+                                    .collect(),
+                            );
+                        }
+                        Some(Err(e)) => {
+                            if e.is_fatal() {
+                                apply(coalescer.drain());
+                            }
+                            let mut status = status.lock().expect("poisoned");
+                            status.record_stream_error(&e);
+                            sink.record(Event::StreamItemFailed { error: e.clone() });
+                            if e.is_fatal() {
+                                status.subscription = SubscriptionState::Failed;
+                                sink.record(Event::StreamStopped { error: e });
+                                return;
+                            }
+                        }
+                        None => break,
+                    }
+                }
+                apply(coalescer.drain());
+
+                // Step 5: Resubscribe after a backoff, starting over at step 1
+                if subscribed_at.elapsed() >= backoff.max {
+                    // the stream stayed up for a while, so the upstream is healthy again
+                    attempt = 0;
+                }
+                let delay = backoff.delay(attempt);
+                attempt = attempt.saturating_add(1);
+                sink.record(Event::StreamEnded {
+                    resubscribe_in: delay,
+                });
+                status.lock().expect("poisoned").subscription = SubscriptionState::Ended;
+                time::sleep(delay).await;
+                {
+                    let mut status = status.lock().expect("poisoned");
+                    status.reconnects += 1;
+                    status.subscription = SubscriptionState::Connecting;
+                }
+                kind = FetchKind::Resubscribe;
             }
-        });        
+        });
     }
 
-    fn subscribe_in_background(&self, api_arc: &Arc<impl Api>) {
+    fn refresh_in_background(&self, api_arc: &Arc<impl Source<K, V>>, interval: Duration) {
         let results = self.results.clone();
+        let watchers = self.watchers.clone();
+        let status = self.status.clone();
+        let ready = self.ready.clone();
+        let sink = self.sink.clone();
         let api = Arc::clone(api_arc);
 
-        spawn(async move {
-            // Step 2: Subscribe to real-time updates
-            let mut updates = api.subscribe().await;
+        self.spawn(async move {
+            loop {
+                time::sleep(interval).await;
 
-            while let Some(update) = updates.next().await {
-                match update {
-                    Ok((city, temperature)) => {
-                        let mut cache = results.lock().expect("poisoned");
-                        cache.insert(city, temperature);
+                // Reconcile with a full snapshot. Updates streamed while
+                // the fetch is in flight win over the (possibly older) snapshot.
+                let issued_at = results.write().issue_fetch();
+                let started = Instant::now();
+                sink.record(Event::FetchStarted {
+                    kind: FetchKind::Refresh,
+                    attempt: 1,
+                });
+                match api.fetch().await {
+                    Ok(snapshot) => {
+                        sink.record(Event::FetchSucceeded {
+                            kind: FetchKind::Refresh,
+                            attempt: 1,
+                            entries: snapshot.len(),
+                            elapsed: started.elapsed(),
+                        });
+                        let mut cache = results.write();
+                        let changes = cache.merge_snapshot(snapshot, issued_at);
+                        {
+                            // the cache is populated even if the initial fetch gave up
+                            let mut status = status.lock().expect("poisoned");
+                            status.fetch = FetchOutcome::Succeeded { attempts: 1 };
+                            if !changes.is_empty() {
+                                status.last_update = Some(Instant::now());
+                            }
+                        }
+                        ready.send_replace(true);
+                        sink.record(Event::EntriesApplied {
+                            source: ChangeSource::Fetch,
+                            count: changes.len(),
+                        });
+                        for change in changes {
+                            watchers.notify(Some(change));
+                        }
                     }
                     Err(e) => {
-                        eprintln!("Failed to get update from subscribe: {}", e);
+                        results.write().end_fetch(issued_at);
+                        status.lock().expect("poisoned").record_fetch_error(&e);
+                        sink.record(Event::FetchFailed {
+                            kind: FetchKind::Refresh,
+                            attempt: 1,
+                            error: e,
+                            elapsed: started.elapsed(),
+                            retry_in: Some(interval),
+                        });
                     }
                 }
             }
         });
     }
 
-    pub fn update_in_background(&self, api: impl Api) {
+    fn expire_in_background(&self, ttl: Duration) {
+        let results = self.results.clone();
+        let watchers = self.watchers.clone();
+        let sink = self.sink.clone();
+
+        self.spawn(async move {
+            loop {
+                time::sleep(ttl).await;
+                let mut cache = results.write();
+                let changes = cache.expire();
+                if !changes.is_empty() {
+                    sink.record(Event::EntriesApplied {
+                        source: ChangeSource::Expire,
+                        count: changes.len(),
+                    });
+                }
+                for change in changes {
+                    watchers.notify(Some(change));
+                }
+            }
+        });
+    }
+
+    pub fn update_in_background(&self, api: impl Api<K, V>) {
+        self.source_in_background(Unversioned(api));
+    }
+
+    pub fn update_versioned_in_background(&self, api: impl VersionedApi<K, V>) {
+        self.source_in_background(Versioned(api));
+    }
+
+    fn source_in_background(&self, api: impl Source<K, V>) {
         let api_arc = Arc::new(api);
-        self.fetch_in_background(&api_arc);
-        self.subscribe_in_background(&api_arc);
+        self.sync_in_background(&api_arc);
+        if let Some(interval) = self.config.refresh_interval {
+            self.refresh_in_background(&api_arc, interval);
+        }
+    }
+}
+
+/// Fetches a snapshot according to `policy`, recording failed attempts in
+/// `status`. Returns the snapshot and the number of attempts it took, or
+/// `None` once the policy gives up.
+async fn fetch_with_retry<K, V>(
+    api: &impl Source<K, V>,
+    kind: FetchKind,
+    policy: &RetryPolicy,
+    status: &Mutex<Status>,
+    sink: &dyn EventSink,
+) -> Option<(Snapshot<K, V>, u32)> {
+    let started = Instant::now();
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        let attempt_started = Instant::now();
+        sink.record(Event::FetchStarted {
+            kind,
+            attempt: attempts,
+        });
+        let result = match policy.remaining(started.elapsed()) {
+            Some(remaining) => time::timeout(remaining, api.fetch())
+                .await
+                .unwrap_or_else(|_| Err(ApiError::Timeout("fetch deadline exceeded".to_string()))),
+            None => api.fetch().await,
+        };
+
+        let e = match result {
+            Ok(snapshot) => {
+                sink.record(Event::FetchSucceeded {
+                    kind,
+                    attempt: attempts,
+                    entries: snapshot.len(),
+                    elapsed: attempt_started.elapsed(),
+                });
+                return Some((snapshot, attempts));
+            }
+            Err(e) => e,
+        };
+        let delay = if e.is_retryable() {
+            policy.next_delay(attempts, started.elapsed())
+        } else {
+            None
+        };
+        sink.record(Event::FetchFailed {
+            kind,
+            attempt: attempts,
+            error: e.clone(),
+            elapsed: attempt_started.elapsed(),
+            retry_in: delay,
+        });
+        {
+            let mut status = status.lock().expect("poisoned");
+            status.record_fetch_error(&e);
+            // later fetches only report success, failing to reconcile does
+            // not undo a successful one
+            if kind == FetchKind::Initial {
+                status.fetch = match delay {
+                    Some(_) => FetchOutcome::Retrying {
+                        attempts,
+                        last_error: e,
+                    },
+                    None => FetchOutcome::Failed {
+                        attempts,
+                        last_error: e,
+                    },
+                };
+            }
+        }
+        time::sleep(delay?).await;
+    }
+}
+
+impl<K, V> Drop for StreamCache<K, V> {
+    fn drop(&mut self) {
+        for task in self.tasks.get_mut().expect("poisoned").iter() {
+            task.abort();
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
     use std::time::Duration;
     use tokio::sync::Notify;
     use tokio::time;
@@ -103,20 +862,32 @@ mod tests {
 
     #[async_trait]
     impl Api for TestApi {
-        async fn fetch(&self) -> Result<HashMap<City, Temperature>, String> {
+        async fn fetch(&self) -> Result<HashMap<City, Temperature>, ApiError> {
             // fetch is slow an may get delayed until after we receive the first updates
             self.signal.notified().await;
             Ok(hashmap! {
-                "Berlin".to_string() => 29,
-                "Paris".to_string() => 31,
+                "Berlin".to_string() => Temperature::celsius(29.0),
+                "Paris".to_string() => Temperature::celsius(31.0),
             })
         }
-        async fn subscribe(&self) -> BoxStream<Result<(City, Temperature), String>> {
+        async fn subscribe(&self) -> BoxStream<Result<Delta, ApiError>> {
             let results = vec![
-                Ok(("London".to_string(), 27)),
-                Ok(("Paris".to_string(), 32)),
-                Ok(("Riga".to_string(), 20)),
-                Ok(("Riga".to_string(), 19)),
+                Ok(Delta::Upsert(
+                    "London".to_string(),
+                    Temperature::celsius(27.0),
+                )),
+                Ok(Delta::Upsert(
+                    "Paris".to_string(),
+                    Temperature::celsius(32.0),
+                )),
+                Ok(Delta::Upsert(
+                    "Riga".to_string(),
+                    Temperature::celsius(20.0),
+                )),
+                Ok(Delta::Upsert(
+                    "Riga".to_string(),
+                    Temperature::celsius(-5.5),
+                )),
             ];
             select(
                 futures::stream::iter(results),
@@ -134,12 +905,1175 @@ mod tests {
         let cache = StreamCache::new(TestApi::default());
 
         // Allow cache to update
-        time::sleep(Duration::from_millis(100)).await;
+        cache.ready().await;
 
-        assert_eq!(cache.get("Berlin"), Some(29));
-        assert_eq!(cache.get("London"), Some(27));
-        assert_eq!(cache.get("Paris"), Some(32));
-        assert_eq!(cache.get("Riga"), Some(19));
+        assert_eq!(cache.get("Berlin"), Some(Temperature::celsius(29.0)));
+        assert_eq!(cache.get("London"), Some(Temperature::celsius(27.0)));
+        assert_eq!(cache.get("Paris"), Some(Temperature::celsius(32.0)));
+        assert_eq!(cache.get("Riga"), Some(Temperature::celsius(-5.5)));
         assert_eq!(cache.get("Tallin"), None);
     }
+
+    #[tokio::test]
+    async fn tracks_entry_metadata() {
+        let cache = StreamCache::new(TestApi::default());
+        cache.ready().await;
+
+        let berlin = cache.get_entry("Berlin").unwrap();
+        assert_eq!(berlin.source, ChangeSource::Fetch);
+        assert_eq!(berlin.update_count, 1);
+        let riga = cache.get_entry("Riga").unwrap();
+        assert_eq!(riga.source, ChangeSource::Subscribe);
+        assert_eq!(riga.value, Temperature::celsius(-5.5));
+        assert!(cache.get_entry("Tallin").is_none());
+    }
+
+    #[derive(Default)]
+    struct FlappingApi {
+        subscriptions: AtomicU64,
+    }
+
+    #[async_trait]
+    impl Api for FlappingApi {
+        async fn fetch(&self) -> Result<HashMap<City, Temperature>, ApiError> {
+            Ok(HashMap::new())
+        }
+        async fn subscribe(&self) -> BoxStream<Result<Delta, ApiError>> {
+            // every subscription delivers a single update and then ends
+            let n = self.subscriptions.fetch_add(1, Ordering::Relaxed);
+            let update = Delta::Upsert("Oslo".to_string(), Temperature::celsius(n as f64));
+            futures::stream::iter(vec![Ok(update)]).boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn resubscribes_when_stream_ends() {
+        let config = Config {
+            resubscribe_backoff: Backoff {
+                initial: Duration::from_millis(5),
+                max: Duration::from_millis(5),
+                multiplier: 1.0,
+                jitter: 0.0,
+            },
+            ..Config::default()
+        };
+        let cache = StreamCache::with_config(FlappingApi::default(), config);
+
+        time::sleep(Duration::from_millis(100)).await;
+
+        let reconnects = cache.reconnect_count();
+        assert!(reconnects >= 3, "only {} reconnects", reconnects);
+        assert!(cache.get("Oslo") >= Some(Temperature::celsius(3.0)));
+    }
+
+    #[tokio::test]
+    async fn backs_off_from_streams_that_end_after_each_item() {
+        let sink = Arc::new(RecordingSink::default());
+        let config = Config {
+            resubscribe_backoff: Backoff {
+                initial: Duration::from_millis(1),
+                max: Duration::from_secs(1),
+                multiplier: 2.0,
+                jitter: 0.0,
+            },
+            event_sink: sink.clone(),
+            ..Config::default()
+        };
+        let cache = StreamCache::with_config(FlappingApi::default(), config);
+
+        time::sleep(Duration::from_millis(50)).await;
+        drop(cache);
+
+        let delays: Vec<_> = sink
+            .events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|event| match event {
+                Event::StreamEnded { resubscribe_in } => Some(resubscribe_in.as_millis()),
+                _ => None,
+            })
+            .take(4)
+            .collect();
+        assert_eq!(delays, vec![1, 2, 4, 8]);
+    }
+
+    #[tokio::test]
+    async fn records_history_of_values() {
+        let start = Instant::now();
+        let config = Config {
+            history: Some(HistoryLimit::Len(2)),
+            ..Config::default()
+        };
+        let cache = StreamCache::with_config(StormApi { updates: 20 }, config);
+
+        cache
+            .watch_key("Oslo")
+            .wait_for(|value| *value >= Some(Temperature::celsius(1.0)))
+            .await
+            .unwrap();
+
+        let history = cache.history("Oslo");
+        assert_eq!(history.len(), 2);
+        let (previous, latest) = (&history[0], &history[1]);
+        assert_eq!(latest.value.as_celsius() - previous.value.as_celsius(), 1.0);
+        // later updates may have pushed either sample out of the history by now
+        for sample in [previous, latest] {
+            let value = cache.value_at("Oslo", sample.at);
+            assert!(value.is_none() || value == Some(sample.value));
+        }
+        assert_eq!(cache.value_at("Oslo", start), None);
+    }
+
+    /// The first subscription ends right away, later ones never deliver.
+    #[derive(Default)]
+    struct EndingApi {
+        upstream: Arc<Mutex<HashMap<City, Temperature>>>,
+        subscriptions: AtomicU64,
+    }
+
+    #[async_trait]
+    impl Api for EndingApi {
+        async fn fetch(&self) -> Result<HashMap<City, Temperature>, ApiError> {
+            Ok(self.upstream.lock().unwrap().clone())
+        }
+        async fn subscribe(&self) -> BoxStream<Result<Delta, ApiError>> {
+            if self.subscriptions.fetch_add(1, Ordering::Relaxed) == 0 {
+                futures::stream::empty().boxed()
+            } else {
+                futures::stream::pending().boxed()
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn reconciles_after_resubscribing() {
+        let api = EndingApi::default();
+        let upstream = api.upstream.clone();
+        upstream
+            .lock()
+            .unwrap()
+            .insert("Rome".to_string(), Temperature::celsius(30.0));
+        let config = Config {
+            resubscribe_backoff: Backoff {
+                initial: Duration::from_millis(20),
+                max: Duration::from_millis(20),
+                multiplier: 1.0,
+                jitter: 0.0,
+            },
+            ..Config::default()
+        };
+        let cache = StreamCache::with_config(api, config);
+        cache.ready().await;
+        assert_eq!(cache.get("Rome"), Some(Temperature::celsius(30.0)));
+
+        // changed while the stream was down, so only the new snapshot has it
+        *upstream.lock().unwrap() = hashmap! { "Madrid".to_string() => Temperature::celsius(33.0) };
+        time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(cache.reconnect_count(), 1);
+        assert_eq!(cache.get("Rome"), None);
+        assert_eq!(cache.get("Madrid"), Some(Temperature::celsius(33.0)));
+    }
+
+    /// Only the first fetch succeeds, and the first subscription ends
+    /// right away.
+    #[derive(Default)]
+    struct FailingResubscribeApi {
+        fetches: AtomicU64,
+        subscriptions: AtomicU64,
+    }
+
+    #[async_trait]
+    impl Api for FailingResubscribeApi {
+        async fn fetch(&self) -> Result<HashMap<City, Temperature>, ApiError> {
+            if self.fetches.fetch_add(1, Ordering::Relaxed) > 0 {
+                return Err(ApiError::Unavailable("upstream unavailable".to_string()));
+            }
+            Ok(hashmap! { "Vienna".to_string() => Temperature::celsius(24.0) })
+        }
+        async fn subscribe(&self) -> BoxStream<Result<Delta, ApiError>> {
+            if self.subscriptions.fetch_add(1, Ordering::Relaxed) == 0 {
+                futures::stream::empty().boxed()
+            } else {
+                futures::stream::pending().boxed()
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_resubscribe_fetch_keeps_fetch_outcome() {
+        let config = Config {
+            resubscribe_backoff: Backoff {
+                initial: Duration::from_millis(5),
+                ..Backoff::default()
+            },
+            ..fast_retry(2)
+        };
+        let cache = StreamCache::with_config(FailingResubscribeApi::default(), config);
+
+        time::sleep(Duration::from_millis(100)).await;
+
+        let status = cache.status();
+        assert_eq!(status.reconnects, 1);
+        assert_eq!(status.fetch_errors, 2);
+        assert_eq!(status.fetch, FetchOutcome::Succeeded { attempts: 1 });
+        assert!(cache.is_ready());
+    }
+
+    struct FlakyFetchApi {
+        failures_left: AtomicU64,
+    }
+
+    #[async_trait]
+    impl Api for FlakyFetchApi {
+        async fn fetch(&self) -> Result<HashMap<City, Temperature>, ApiError> {
+            let failures_left = self.failures_left.load(Ordering::Relaxed);
+            if failures_left > 0 {
+                self.failures_left
+                    .store(failures_left - 1, Ordering::Relaxed);
+                return Err(ApiError::Unavailable("upstream unavailable".to_string()));
+            }
+            Ok(hashmap! { "Vienna".to_string() => Temperature::celsius(24.0) })
+        }
+        async fn subscribe(&self) -> BoxStream<Result<Delta, ApiError>> {
+            futures::stream::pending().boxed()
+        }
+    }
+
+    fn fast_retry(max_attempts: u32) -> Config {
+        Config {
+            fetch_retry: RetryPolicy {
+                max_attempts,
+                backoff: Backoff {
+                    initial: Duration::from_millis(5),
+                    ..Backoff::default()
+                },
+                deadline: None,
+            },
+            ..Config::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_failed_fetch() {
+        let api = FlakyFetchApi {
+            failures_left: AtomicU64::new(2),
+        };
+        let cache = StreamCache::with_config(api, fast_retry(5));
+
+        time::sleep(Duration::from_millis(100)).await;
+
+        assert_eq!(
+            cache.fetch_outcome(),
+            FetchOutcome::Succeeded { attempts: 3 }
+        );
+        assert_eq!(cache.get("Vienna"), Some(Temperature::celsius(24.0)));
+    }
+
+    #[tokio::test]
+    async fn reports_exhausted_fetch_retries() {
+        let api = FlakyFetchApi {
+            failures_left: AtomicU64::new(u64::MAX),
+        };
+        let cache = StreamCache::with_config(api, fast_retry(2));
+
+        time::sleep(Duration::from_millis(100)).await;
+
+        assert_eq!(
+            cache.fetch_outcome(),
+            FetchOutcome::Failed {
+                attempts: 2,
+                last_error: ApiError::Unavailable("upstream unavailable".to_string())
+            }
+        );
+        assert_eq!(cache.get("Vienna"), None);
+    }
+
+    #[tokio::test]
+    async fn becomes_ready_once_a_refresh_succeeds() {
+        let api = FlakyFetchApi {
+            failures_left: AtomicU64::new(1),
+        };
+        let config = Config {
+            refresh_interval: Some(Duration::from_millis(20)),
+            ..fast_retry(1)
+        };
+        let cache = StreamCache::with_config(api, config);
+
+        time::sleep(Duration::from_millis(10)).await;
+        assert!(!cache.is_ready());
+        assert!(matches!(cache.fetch_outcome(), FetchOutcome::Failed { .. }));
+
+        time::timeout(Duration::from_millis(100), cache.ready())
+            .await
+            .expect("ready after refresh");
+        assert_eq!(
+            cache.fetch_outcome(),
+            FetchOutcome::Succeeded { attempts: 1 }
+        );
+        assert_eq!(cache.get("Vienna"), Some(Temperature::celsius(24.0)));
+    }
+
+    #[derive(Default)]
+    struct DriftingApi {
+        upstream: Arc<Mutex<HashMap<City, Temperature>>>,
+    }
+
+    #[async_trait]
+    impl Api for DriftingApi {
+        async fn fetch(&self) -> Result<HashMap<City, Temperature>, ApiError> {
+            Ok(self.upstream.lock().unwrap().clone())
+        }
+        async fn subscribe(&self) -> BoxStream<Result<Delta, ApiError>> {
+            // the stream never delivers the changes made to `upstream`
+            futures::stream::pending().boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn periodic_refresh_repairs_missed_updates() {
+        let api = DriftingApi::default();
+        let upstream = api.upstream.clone();
+        upstream
+            .lock()
+            .unwrap()
+            .insert("Madrid".to_string(), Temperature::celsius(33.0));
+
+        let config = Config {
+            refresh_interval: Some(Duration::from_millis(20)),
+            ..Config::default()
+        };
+        let cache = StreamCache::with_config(api, config);
+
+        time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(cache.get("Madrid"), Some(Temperature::celsius(33.0)));
+
+        upstream
+            .lock()
+            .unwrap()
+            .insert("Madrid".to_string(), Temperature::celsius(35.0));
+        time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(cache.get("Madrid"), Some(Temperature::celsius(35.0)));
+    }
+
+    #[tokio::test]
+    async fn unchanged_refresh_does_not_wake_key_watchers() {
+        let api = DriftingApi::default();
+        api.upstream
+            .lock()
+            .unwrap()
+            .insert("Rome".to_string(), Temperature::celsius(30.0));
+        let config = Config {
+            refresh_interval: Some(Duration::from_millis(10)),
+            ..Config::default()
+        };
+        let cache = StreamCache::with_config(api, config);
+        cache.ready().await;
+        let rome = cache.watch_key("Rome");
+        let fetched_at = cache.get_entry("Rome").unwrap().updated_at;
+
+        time::sleep(Duration::from_millis(50)).await;
+
+        assert!(!rome.has_changed().unwrap());
+        let entry = cache.get_entry("Rome").unwrap();
+        assert_eq!(entry.update_count, 1);
+        assert!(entry.updated_at > fetched_at);
+    }
+
+    #[tokio::test]
+    async fn maintains_aggregates() {
+        let api = DriftingApi::default();
+        let upstream = api.upstream.clone();
+        *upstream.lock().unwrap() = hashmap! {
+            "Oslo".to_string() => Temperature::celsius(-4.5),
+            "Rome".to_string() => Temperature::celsius(18.0),
+            "Cairo".to_string() => Temperature::fahrenheit(86.0),
+        };
+
+        let config = Config {
+            refresh_interval: Some(Duration::from_millis(20)),
+            ..Config::default()
+        };
+        let cache = StreamCache::with_config(api, config);
+        cache.ready().await;
+        assert_eq!(
+            cache.aggregate(),
+            Some(Aggregate {
+                count: 3,
+                min: -4.5,
+                max: 30.0,
+                mean: 14.5,
+                median: 18.0,
+            })
+        );
+
+        {
+            let mut upstream = upstream.lock().unwrap();
+            upstream.remove("Cairo");
+            upstream.insert("Rome".to_string(), Temperature::celsius(20.5));
+        }
+        time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(
+            cache.aggregate(),
+            Some(Aggregate {
+                count: 2,
+                min: -4.5,
+                max: 20.5,
+                mean: 8.0,
+                median: 8.0,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn queries_keys_by_value() {
+        let api = DriftingApi::default();
+        let upstream = api.upstream.clone();
+        *upstream.lock().unwrap() = hashmap! {
+            "Oslo".to_string() => Temperature::celsius(-4.5),
+            "Rome".to_string() => Temperature::celsius(27.0),
+            "Cairo".to_string() => Temperature::celsius(35.0),
+        };
+
+        let config = Config {
+            refresh_interval: Some(Duration::from_millis(20)),
+            ..Config::default()
+        };
+        let cache = StreamCache::with_config(api, config);
+        cache.ready().await;
+        let warm = Temperature::celsius(25.0)..=Temperature::celsius(30.0);
+        assert_eq!(
+            cache.range(warm.clone()),
+            vec![("Rome".to_string(), Temperature::celsius(27.0))]
+        );
+
+        {
+            let mut upstream = upstream.lock().unwrap();
+            upstream.remove("Cairo");
+            upstream.insert("Lima".to_string(), Temperature::celsius(26.0));
+        }
+        time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(
+            cache.range(warm),
+            vec![
+                ("Lima".to_string(), Temperature::celsius(26.0)),
+                ("Rome".to_string(), Temperature::celsius(27.0)),
+            ]
+        );
+        assert_eq!(
+            cache.top_n(1),
+            vec![("Rome".to_string(), Temperature::celsius(27.0))]
+        );
+    }
+
+    #[tokio::test]
+    async fn streams_alerts_for_rules() {
+        let api = DriftingApi::default();
+        let upstream = api.upstream.clone();
+        upstream
+            .lock()
+            .unwrap()
+            .insert("Rome".to_string(), Temperature::celsius(27.0));
+
+        let config = Config {
+            refresh_interval: Some(Duration::from_millis(10)),
+            ..Config::default()
+        };
+        let cache = StreamCache::with_config(api, config);
+        cache.ready().await;
+        let mut alerts = cache.alerts(vec![Rule {
+            name: "heat".to_string(),
+            key: Some("Rome".to_string()),
+            condition: Condition::Above(30.0),
+            for_duration: Duration::from_millis(30),
+        }]);
+
+        let heat = Instant::now();
+        upstream
+            .lock()
+            .unwrap()
+            .insert("Rome".to_string(), Temperature::celsius(33.0));
+        let fired = alerts.next().await.unwrap();
+        assert!(heat.elapsed() >= Duration::from_millis(30));
+        assert_eq!(
+            fired,
+            Alert {
+                rule: "heat".to_string(),
+                key: "Rome".to_string(),
+                state: AlertState::Fired,
+                value: Some(33.0),
+            }
+        );
+
+        upstream
+            .lock()
+            .unwrap()
+            .insert("Rome".to_string(), Temperature::celsius(25.0));
+        let resolved = alerts.next().await.unwrap();
+        assert_eq!(resolved.state, AlertState::Resolved);
+        assert_eq!(resolved.value, Some(25.0));
+    }
+
+    #[tokio::test]
+    async fn dropped_alert_streams_stop_their_task() {
+        let cache = StreamCache::new(DriftingApi::default());
+        cache.ready().await;
+        let tasks = || cache.tasks.lock().unwrap().len();
+        assert_eq!(tasks(), 1);
+
+        for _ in 0..3 {
+            drop(cache.alerts(Vec::new()));
+            time::sleep(Duration::from_millis(1)).await;
+        }
+        let _alerts = cache.alerts(Vec::new());
+
+        assert_eq!(tasks(), 2);
+    }
+
+    #[tokio::test]
+    async fn refresh_removes_keys_no_longer_tracked() {
+        let api = DriftingApi::default();
+        let upstream = api.upstream.clone();
+        upstream
+            .lock()
+            .unwrap()
+            .insert("Riga".to_string(), Temperature::celsius(18.0));
+
+        let config = Config {
+            refresh_interval: Some(Duration::from_millis(20)),
+            ..Config::default()
+        };
+        let cache = StreamCache::with_config(api, config);
+        cache.ready().await;
+        let mut riga = cache.watch_key("Riga");
+        assert_eq!(*riga.borrow_and_update(), Some(Temperature::celsius(18.0)));
+
+        upstream.lock().unwrap().remove("Riga");
+        riga.changed().await.unwrap();
+
+        assert_eq!(*riga.borrow(), None);
+        assert_eq!(cache.get("Riga"), None);
+    }
+
+    #[derive(Default)]
+    struct RecordingApi {
+        calls: Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    #[async_trait]
+    impl Api for RecordingApi {
+        async fn fetch(&self) -> Result<HashMap<City, Temperature>, ApiError> {
+            self.calls.lock().unwrap().push("fetch");
+            Ok(hashmap! { "Rome".to_string() => Temperature::celsius(30.0) })
+        }
+        async fn subscribe(&self) -> BoxStream<Result<Delta, ApiError>> {
+            self.calls.lock().unwrap().push("subscribe");
+            futures::stream::pending().boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn subscribes_before_fetching() {
+        let api = RecordingApi::default();
+        let calls = api.calls.clone();
+        let cache = StreamCache::new(api);
+
+        time::sleep(Duration::from_millis(10)).await;
+
+        assert_eq!(*calls.lock().unwrap(), vec!["subscribe", "fetch"]);
+        assert_eq!(cache.get("Rome"), Some(Temperature::celsius(30.0)));
+    }
+
+    #[derive(Default)]
+    struct VersionedTestApi {
+        signal: Arc<Notify>,
+    }
+
+    #[async_trait]
+    impl VersionedApi for VersionedTestApi {
+        async fn fetch(&self) -> Result<HashMap<City, (Temperature, Version)>, ApiError> {
+            self.signal.notified().await;
+            Ok(hashmap! {
+                "Berlin".to_string() => (Temperature::celsius(29.0), 10),
+                "Paris".to_string() => (Temperature::celsius(31.0), 12),
+            })
+        }
+        async fn subscribe(&self) -> BoxStream<Result<(Delta, Version), ApiError>> {
+            let results = vec![
+                // delayed in transit, older than the snapshot
+                Ok((
+                    Delta::Upsert("Paris".to_string(), Temperature::celsius(32.0)),
+                    11,
+                )),
+                Ok((
+                    Delta::Upsert("Berlin".to_string(), Temperature::celsius(28.0)),
+                    13,
+                )),
+                Ok((
+                    Delta::Upsert("Riga".to_string(), Temperature::celsius(20.0)),
+                    14,
+                )),
+                // reordered, older than the previous Riga update
+                Ok((
+                    Delta::Upsert("Riga".to_string(), Temperature::celsius(19.0)),
+                    13,
+                )),
+            ];
+            select(
+                futures::stream::iter(results),
+                async {
+                    self.signal.notify_one();
+                    future::pending().await
+                }
+                .into_stream(),
+            )
+            .boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn versioned_api_keeps_newest_version() {
+        let cache = StreamCache::versioned(VersionedTestApi::default());
+
+        time::sleep(Duration::from_millis(100)).await;
+
+        assert_eq!(cache.get("Berlin"), Some(Temperature::celsius(28.0)));
+        assert_eq!(cache.get("Paris"), Some(Temperature::celsius(31.0)));
+        assert_eq!(cache.get("Riga"), Some(Temperature::celsius(20.0)));
+    }
+
+    /// Holds a clone of `alive` for as long as the cache holds the api.
+    struct TrackedApi {
+        _alive: Arc<()>,
+    }
+
+    #[async_trait]
+    impl Api for TrackedApi {
+        async fn fetch(&self) -> Result<HashMap<City, Temperature>, ApiError> {
+            Ok(HashMap::new())
+        }
+        async fn subscribe(&self) -> BoxStream<Result<Delta, ApiError>> {
+            futures::stream::pending().boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_releases_api() {
+        let alive = Arc::new(());
+        let config = Config {
+            refresh_interval: Some(Duration::from_millis(5)),
+            ..Config::default()
+        };
+        let cache = StreamCache::with_config(
+            TrackedApi {
+                _alive: alive.clone(),
+            },
+            config,
+        );
+        time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(Arc::strong_count(&alive), 2);
+
+        cache.shutdown().await;
+
+        assert_eq!(Arc::strong_count(&alive), 1);
+    }
+
+    #[tokio::test]
+    async fn drop_aborts_background_tasks() {
+        let alive = Arc::new(());
+        let cache = StreamCache::new(TrackedApi {
+            _alive: alive.clone(),
+        });
+        time::sleep(Duration::from_millis(10)).await;
+
+        drop(cache);
+        time::sleep(Duration::from_millis(10)).await;
+
+        assert_eq!(Arc::strong_count(&alive), 1);
+    }
+
+    struct FeatureFlagApi;
+
+    #[async_trait]
+    impl Api<&'static str, bool> for FeatureFlagApi {
+        async fn fetch(&self) -> Result<HashMap<&'static str, bool>, ApiError> {
+            Ok(hashmap! { "dark-mode" => false, "beta-search" => true })
+        }
+        async fn subscribe(&self) -> BoxStream<Result<Delta<&'static str, bool>, ApiError>> {
+            futures::stream::iter(vec![Ok(Delta::Upsert("dark-mode", true))])
+                .chain(futures::stream::pending())
+                .boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn caches_arbitrary_keys_and_values() {
+        let cache: StreamCache<&'static str, bool> = StreamCache::new(FeatureFlagApi);
+
+        time::sleep(Duration::from_millis(10)).await;
+
+        assert_eq!(cache.get("dark-mode"), Some(true));
+        assert_eq!(cache.get("beta-search"), Some(true));
+        assert_eq!(cache.get("legacy-ui"), None);
+    }
+
+    #[tokio::test]
+    async fn snapshot_lists_all_keys() {
+        let cache: StreamCache<&'static str, bool> = StreamCache::new(FeatureFlagApi);
+        assert!(cache.snapshot().is_empty());
+
+        cache.ready().await;
+
+        let snapshot = cache.snapshot();
+        let mut keys: Vec<_> = snapshot.keys().copied().collect();
+        keys.sort();
+        assert_eq!(keys, vec!["beta-search", "dark-mode"]);
+        assert_eq!(snapshot.get("dark-mode"), Some(&true));
+        assert_eq!(snapshot.clone().iter().filter(|(_, on)| **on).count(), 2);
+    }
+
+    #[tokio::test]
+    async fn becomes_ready_after_initial_fetch() {
+        let api = FlakyFetchApi {
+            failures_left: AtomicU64::new(1),
+        };
+        let cache = StreamCache::with_config(api, fast_retry(5));
+        assert!(!cache.is_ready());
+
+        assert!(cache.wait_until_initialized(Duration::from_secs(1)).await);
+        assert!(cache.is_ready());
+        assert_eq!(cache.get("Vienna"), Some(Temperature::celsius(24.0)));
+    }
+
+    #[tokio::test]
+    async fn never_ready_when_fetch_fails() {
+        let api = FlakyFetchApi {
+            failures_left: AtomicU64::new(u64::MAX),
+        };
+        let cache = StreamCache::with_config(api, fast_retry(2));
+
+        assert!(
+            !cache
+                .wait_until_initialized(Duration::from_millis(50))
+                .await
+        );
+        assert!(!cache.is_ready());
+    }
+
+    #[tokio::test]
+    async fn watch_streams_applied_changes() {
+        let cache: StreamCache<&'static str, bool> = StreamCache::new(FeatureFlagApi);
+        let mut changes = cache.watch();
+
+        let mut fetched = vec![
+            changes.next().await.unwrap().unwrap(),
+            changes.next().await.unwrap().unwrap(),
+        ];
+        fetched.sort_by_key(|change| change.key);
+        assert_eq!(
+            fetched,
+            vec![
+                Change {
+                    key: "beta-search",
+                    old: None,
+                    new: Some(true),
+                    source: ChangeSource::Fetch,
+                },
+                Change {
+                    key: "dark-mode",
+                    old: None,
+                    new: Some(false),
+                    source: ChangeSource::Fetch,
+                },
+            ]
+        );
+        assert_eq!(
+            changes.next().await,
+            Some(Ok(Change {
+                key: "dark-mode",
+                old: Some(false),
+                new: Some(true),
+                source: ChangeSource::Subscribe,
+            }))
+        );
+
+        cache.shutdown().await;
+        assert_eq!(changes.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn buffers_one_change_at_zero_watch_capacity() {
+        let config = Config {
+            watch_capacity: 0,
+            ..Config::default()
+        };
+        let cache = StreamCache::with_config(TestApi::default(), config);
+        let mut changes = cache.watch();
+        let mut alerts = cache.alerts(vec![Rule {
+            name: "frost".to_string(),
+            key: Some("Riga".to_string()),
+            condition: Condition::Below(0.0),
+            for_duration: Duration::ZERO,
+        }]);
+
+        assert!(changes.next().await.is_some());
+        assert_eq!(alerts.next().await.unwrap().state, AlertState::Fired);
+    }
+
+    #[tokio::test]
+    async fn watch_key_follows_single_key() {
+        let cache: StreamCache<&'static str, bool> = StreamCache::new(FeatureFlagApi);
+        let mut dark_mode = cache.watch_key("dark-mode");
+        assert_eq!(*dark_mode.borrow(), None);
+
+        let value = dark_mode.wait_for(|value| *value == Some(true)).await;
+        assert!(value.is_ok());
+        assert_eq!(*cache.watch_key("dark-mode").borrow(), Some(true));
+    }
+
+    #[tokio::test]
+    async fn expires_entries_not_refreshed_within_ttl() {
+        let config = Config {
+            ttl: Some(Duration::from_millis(30)),
+            ..Config::default()
+        };
+        let cache: StreamCache<&'static str, bool> =
+            StreamCache::with_config(FeatureFlagApi, config);
+        cache.ready().await;
+
+        assert_eq!(cache.get("dark-mode"), Some(true));
+        assert_eq!(
+            cache.get_fresh("dark-mode", Duration::from_secs(1)),
+            Some(true)
+        );
+        time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(cache.get_fresh("dark-mode", Duration::from_millis(5)), None);
+
+        time::sleep(Duration::from_millis(40)).await;
+        assert_eq!(cache.get("dark-mode"), None);
+        assert_eq!(cache.get_entry("beta-search"), None);
+    }
+
+    #[tokio::test]
+    async fn notifies_watchers_of_expired_entries() {
+        let config = Config {
+            ttl: Some(Duration::from_millis(30)),
+            ..Config::default()
+        };
+        let cache: StreamCache<&'static str, bool> =
+            StreamCache::with_config(FeatureFlagApi, config);
+        cache.ready().await;
+        let mut dark_mode = cache.watch_key("dark-mode");
+        let changes = cache.watch();
+
+        let expired = dark_mode.wait_for(|value| value.is_none());
+        assert!(time::timeout(Duration::from_millis(200), expired)
+            .await
+            .is_ok());
+        assert_eq!(*cache.watch_key("dark-mode").borrow(), None);
+
+        let mut expired: Vec<_> = changes
+            .filter_map(|change| future::ready(change.ok()))
+            .filter(|change| future::ready(change.source == ChangeSource::Expire))
+            .take(2)
+            .map(|change| (change.key, change.new))
+            .collect()
+            .await;
+        expired.sort();
+        assert_eq!(expired, vec![("beta-search", None), ("dark-mode", None)]);
+    }
+
+    #[tokio::test]
+    async fn reports_pipeline_status() {
+        let api = FlakyFetchApi {
+            failures_left: AtomicU64::new(2),
+        };
+        let cache = StreamCache::with_config(api, fast_retry(5));
+        assert_eq!(cache.status().subscription, SubscriptionState::Connecting);
+        assert_eq!(cache.status().last_update, None);
+
+        cache.ready().await;
+
+        let status = cache.status();
+        assert_eq!(status.fetch, FetchOutcome::Succeeded { attempts: 3 });
+        assert_eq!(status.subscription, SubscriptionState::Streaming);
+        assert!(status.last_update.is_some());
+        assert_eq!(status.fetch_errors, 2);
+        assert_eq!(
+            status.last_fetch_error,
+            Some(ApiError::Unavailable("upstream unavailable".to_string()))
+        );
+        assert_eq!(status.stream_errors, 0);
+        assert_eq!(status.reconnects, 0);
+    }
+
+    struct ScriptedApi {
+        fetch_error: Option<ApiError>,
+        updates: Vec<Result<Delta, ApiError>>,
+    }
+
+    #[async_trait]
+    impl Api for ScriptedApi {
+        async fn fetch(&self) -> Result<HashMap<City, Temperature>, ApiError> {
+            match &self.fetch_error {
+                Some(e) => Err(e.clone()),
+                None => Ok(HashMap::new()),
+            }
+        }
+        async fn subscribe(&self) -> BoxStream<Result<Delta, ApiError>> {
+            futures::stream::iter(self.updates.clone())
+                .chain(futures::stream::pending())
+                .boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_fatal_fetch_errors() {
+        let unauthorized = ApiError::Unauthorized("token expired".to_string());
+        let api = ScriptedApi {
+            fetch_error: Some(unauthorized.clone()),
+            updates: Vec::new(),
+        };
+        let cache = StreamCache::with_config(api, fast_retry(5));
+
+        time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(
+            cache.fetch_outcome(),
+            FetchOutcome::Failed {
+                attempts: 1,
+                last_error: unauthorized
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn skips_malformed_records_and_stops_on_fatal_stream_errors() {
+        let api = ScriptedApi {
+            fetch_error: None,
+            updates: vec![
+                Err(ApiError::Malformed("missing temperature".to_string())),
+                Ok(Delta::Upsert(
+                    "Lisbon".to_string(),
+                    Temperature::celsius(26.0),
+                )),
+                Err(ApiError::Unauthorized("token expired".to_string())),
+                Ok(Delta::Upsert(
+                    "Lisbon".to_string(),
+                    Temperature::celsius(27.0),
+                )),
+            ],
+        };
+        let cache = StreamCache::new(api);
+        cache.ready().await;
+
+        time::sleep(Duration::from_millis(10)).await;
+
+        assert_eq!(cache.get("Lisbon"), Some(Temperature::celsius(26.0)));
+        let status = cache.status();
+        assert_eq!(status.subscription, SubscriptionState::Failed);
+        assert_eq!(status.stream_errors, 2);
+        assert_eq!(status.reconnects, 0);
+    }
+
+    #[tokio::test]
+    async fn applies_streamed_deletions() {
+        let api = ScriptedApi {
+            fetch_error: None,
+            updates: vec![
+                Ok(Delta::Upsert(
+                    "Lisbon".to_string(),
+                    Temperature::celsius(26.0),
+                )),
+                Ok(Delta::Upsert(
+                    "Porto".to_string(),
+                    Temperature::celsius(24.0),
+                )),
+                Ok(Delta::Delete("Lisbon".to_string())),
+                Ok(Delta::Delete("Faro".to_string())),
+            ],
+        };
+        let cache = StreamCache::new(api);
+        cache.ready().await;
+
+        time::sleep(Duration::from_millis(10)).await;
+
+        assert_eq!(cache.get("Lisbon"), None);
+        assert_eq!(cache.get("Porto"), Some(Temperature::celsius(24.0)));
+        assert_eq!(cache.snapshot().len(), 1);
+    }
+
+    /// Streams `updates` rising values for Oslo, one every 2ms.
+    struct StormApi {
+        updates: u64,
+    }
+
+    #[async_trait]
+    impl Api for StormApi {
+        async fn fetch(&self) -> Result<HashMap<City, Temperature>, ApiError> {
+            Ok(HashMap::new())
+        }
+        async fn subscribe(&self) -> BoxStream<Result<Delta, ApiError>> {
+            futures::stream::iter(0..self.updates)
+                .then(|n| async move {
+                    time::sleep(Duration::from_millis(2)).await;
+                    Ok(Delta::Upsert(
+                        "Oslo".to_string(),
+                        Temperature::celsius(n as f64),
+                    ))
+                })
+                .chain(futures::stream::pending())
+                .boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn coalesces_update_storms() {
+        let config = Config {
+            coalesce_interval: Some(Duration::from_millis(100)),
+            ..Config::default()
+        };
+        let cache = StreamCache::with_config(StormApi { updates: 20 }, config);
+        cache.ready().await;
+        let oslo = cache.watch_key("Oslo");
+
+        time::sleep(Duration::from_millis(150)).await;
+
+        assert_eq!(cache.get("Oslo"), Some(Temperature::celsius(19.0)));
+        assert!(oslo.has_changed().unwrap());
+        let applied = cache.get_entry("Oslo").unwrap().update_count;
+        assert!(applied <= 3, "applied {} of 20 updates", applied);
+    }
+
+    /// Streams two updates of Oslo once the initial fetch is done. Only the
+    /// first fetch issued after they were streamed has Oslo, at a newer value,
+    /// and all later ones fail.
+    #[derive(Default)]
+    struct RefreshRaceApi {
+        streamed: Arc<AtomicBool>,
+        fetched: AtomicBool,
+    }
+
+    #[async_trait]
+    impl Api for RefreshRaceApi {
+        async fn fetch(&self) -> Result<HashMap<City, Temperature>, ApiError> {
+            if !self.streamed.load(Ordering::Relaxed) {
+                return Ok(HashMap::new());
+            }
+            if self.fetched.swap(true, Ordering::Relaxed) {
+                return Err(ApiError::Unavailable("upstream unavailable".to_string()));
+            }
+            Ok(hashmap! { "Oslo".to_string() => Temperature::celsius(5.0) })
+        }
+        async fn subscribe(&self) -> BoxStream<Result<Delta, ApiError>> {
+            let streamed = self.streamed.clone();
+            futures::stream::iter([1.0, 2.0])
+                .then(move |value| {
+                    let streamed = streamed.clone();
+                    async move {
+                        time::sleep(Duration::from_millis(10)).await;
+                        streamed.store(value == 2.0, Ordering::Relaxed);
+                        Ok(Delta::Upsert(
+                            "Oslo".to_string(),
+                            Temperature::celsius(value),
+                        ))
+                    }
+                })
+                .chain(futures::stream::pending())
+                .boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn held_back_updates_do_not_overwrite_newer_snapshots() {
+        let config = Config {
+            refresh_interval: Some(Duration::from_millis(5)),
+            coalesce_interval: Some(Duration::from_millis(100)),
+            ..Config::default()
+        };
+        let cache = StreamCache::with_config(RefreshRaceApi::default(), config);
+        let mut oslo = cache.watch_key("Oslo");
+        oslo.wait_for(|value| *value == Some(Temperature::celsius(5.0)))
+            .await
+            .unwrap();
+
+        // the update held back since before the refresh is due by now
+        time::sleep(Duration::from_millis(150)).await;
+
+        assert_eq!(cache.get("Oslo"), Some(Temperature::celsius(5.0)));
+    }
+
+    #[derive(Default)]
+    struct RecordingSink {
+        events: Mutex<Vec<Event>>,
+    }
+
+    impl EventSink for RecordingSink {
+        fn record(&self, event: Event) {
+            self.events.lock().unwrap().push(event);
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_events_to_sink() {
+        let sink = Arc::new(RecordingSink::default());
+        let api = FlakyFetchApi {
+            failures_left: AtomicU64::new(1),
+        };
+        let config = Config {
+            event_sink: sink.clone(),
+            ..fast_retry(5)
+        };
+        let cache = StreamCache::with_config(api, config);
+        cache.ready().await;
+
+        let events = sink.events.lock().unwrap().clone();
+        let fetch_events: Vec<_> = events
+            .iter()
+            .map(|event| match event {
+                Event::FetchStarted { attempt, .. } => format!("started {}", attempt),
+                Event::FetchFailed {
+                    attempt, retry_in, ..
+                } => format!("failed {} retry {}", attempt, retry_in.is_some()),
+                Event::FetchSucceeded {
+                    attempt, entries, ..
+                } => format!("succeeded {} with {}", attempt, entries),
+                Event::EntriesApplied { source, count } => {
+                    format!("applied {} from {:?}", count, source)
+                }
+                other => format!("{:?}", other),
+            })
+            .collect();
+        assert_eq!(
+            fetch_events,
+            vec![
+                "started 1",
+                "failed 1 retry true",
+                "started 2",
+                "succeeded 2 with 1",
+                "applied 1 from Fetch",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn exposes_metrics() {
+        let cache: StreamCache<&'static str, bool> = StreamCache::new(FeatureFlagApi);
+        cache
+            .watch_key("dark-mode")
+            .wait_for(|value| *value == Some(true))
+            .await
+            .unwrap();
+
+        assert_eq!(cache.metrics().updates_applied(ChangeSource::Fetch), 2);
+        assert_eq!(cache.metrics().updates_applied(ChangeSource::Subscribe), 1);
+        assert_eq!(cache.metrics().fetch_duration().count(), 1);
+
+        let text = cache.render_metrics();
+        assert!(text.contains("\nstreamed_cache_keys 2\n"), "{}", text);
+        assert!(text.contains("\nstreamed_cache_seconds_since_last_update "));
+    }
 }
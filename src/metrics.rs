@@ -0,0 +1,273 @@
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{ChangeSource, Event, EventSink, Status};
+
+/// Upper bounds of the fetch latency histogram buckets, in seconds.
+const FETCH_DURATION_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Counters derived from the [`Event`]s of a cache. Gauges such as the number
+/// of keys are read from the cache itself when rendering, see
+/// [`StreamCache::render_metrics`](crate::StreamCache::render_metrics).
+#[derive(Debug, Default)]
+pub struct Metrics {
+    updates_from_fetch: AtomicU64,
+    updates_from_subscribe: AtomicU64,
+    updates_from_expire: AtomicU64,
+    fetch_duration: Histogram,
+}
+
+impl Metrics {
+    /// Number of values written to, or for [`ChangeSource::Expire`] removed
+    /// from, the cache by `source`.
+    pub fn updates_applied(&self, source: ChangeSource) -> u64 {
+        match source {
+            ChangeSource::Fetch => self.updates_from_fetch.load(Ordering::Relaxed),
+            ChangeSource::Subscribe => self.updates_from_subscribe.load(Ordering::Relaxed),
+            ChangeSource::Expire => self.updates_from_expire.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Latency of completed `fetch` attempts, successful or not.
+    pub fn fetch_duration(&self) -> &Histogram {
+        &self.fetch_duration
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format.
+    pub(crate) fn render(&self, keys: usize, status: &Status) -> String {
+        let mut out = String::new();
+
+        gauge(&mut out, "keys", "Number of cached keys.", keys as f64);
+
+        header(
+            &mut out,
+            "updates_applied_total",
+            "counter",
+            "Values written to the cache, by source.",
+        );
+        for (label, source) in [
+            ("fetch", ChangeSource::Fetch),
+            ("subscribe", ChangeSource::Subscribe),
+            ("expire", ChangeSource::Expire),
+        ] {
+            let _ = writeln!(
+                out,
+                "streamed_cache_updates_applied_total{{source=\"{}\"}} {}",
+                label,
+                self.updates_applied(source)
+            );
+        }
+
+        counter(
+            &mut out,
+            "fetch_errors_total",
+            "Failed fetch attempts.",
+            status.fetch_errors,
+        );
+        counter(
+            &mut out,
+            "stream_errors_total",
+            "Errors delivered by the subscribe stream.",
+            status.stream_errors,
+        );
+        counter(
+            &mut out,
+            "reconnects_total",
+            "Resubscribes after the subscribe stream ended.",
+            status.reconnects,
+        );
+
+        header(
+            &mut out,
+            "fetch_duration_seconds",
+            "histogram",
+            "Latency of fetch attempts.",
+        );
+        let mut cumulative = 0;
+        for (bound, bucket) in FETCH_DURATION_BUCKETS
+            .iter()
+            .zip(&self.fetch_duration.buckets)
+        {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "streamed_cache_fetch_duration_seconds_bucket{{le=\"{}\"}} {}",
+                bound, cumulative
+            );
+        }
+        let count = self.fetch_duration.count();
+        let _ = writeln!(
+            out,
+            "streamed_cache_fetch_duration_seconds_bucket{{le=\"+Inf\"}} {}",
+            count
+        );
+        let _ = writeln!(
+            out,
+            "streamed_cache_fetch_duration_seconds_sum {}",
+            self.fetch_duration.sum().as_secs_f64()
+        );
+        let _ = writeln!(out, "streamed_cache_fetch_duration_seconds_count {}", count);
+
+        // left out until the first update, as there is no meaningful value
+        if let Some(last_update) = status.last_update {
+            gauge(
+                &mut out,
+                "seconds_since_last_update",
+                "Time since a value was last written to the cache.",
+                Instant::now().duration_since(last_update).as_secs_f64(),
+            );
+        }
+
+        out
+    }
+}
+
+impl EventSink for Metrics {
+    fn record(&self, event: Event) {
+        match event {
+            Event::EntriesApplied { source, count } => {
+                let counter = match source {
+                    ChangeSource::Fetch => &self.updates_from_fetch,
+                    ChangeSource::Subscribe => &self.updates_from_subscribe,
+                    ChangeSource::Expire => &self.updates_from_expire,
+                };
+                counter.fetch_add(count as u64, Ordering::Relaxed);
+            }
+            Event::FetchSucceeded { elapsed, .. } | Event::FetchFailed { elapsed, .. } => {
+                self.fetch_duration.observe(elapsed);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Latency histogram with fixed buckets, see [`FETCH_DURATION_BUCKETS`].
+#[derive(Debug)]
+pub struct Histogram {
+    /// Non-cumulative count per bucket; observations above the last bound
+    /// are only part of `count`.
+    buckets: Vec<AtomicU64>,
+    sum_nanos: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: FETCH_DURATION_BUCKETS
+                .iter()
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            sum_nanos: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        if let Some(index) = FETCH_DURATION_BUCKETS
+            .iter()
+            .position(|bound| secs <= *bound)
+        {
+            self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        }
+        let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+        self.sum_nanos.fetch_add(nanos, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn sum(&self) -> Duration {
+        Duration::from_nanos(self.sum_nanos.load(Ordering::Relaxed))
+    }
+}
+
+/// Forwards events to the cache's [`Metrics`] and to the configured sink.
+pub(crate) struct Tee {
+    pub metrics: Arc<Metrics>,
+    pub sink: Arc<dyn EventSink>,
+}
+
+impl EventSink for Tee {
+    fn record(&self, event: Event) {
+        self.metrics.record(event.clone());
+        self.sink.record(event);
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP streamed_cache_{} {}", name, help);
+    let _ = writeln!(out, "# TYPE streamed_cache_{} {}", name, kind);
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: f64) {
+    header(out, name, "gauge", help);
+    let _ = writeln!(out, "streamed_cache_{} {}", name, value);
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    header(out, name, "counter", help);
+    let _ = writeln!(out, "streamed_cache_{} {}", name, value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_prometheus_text() {
+        let metrics = Metrics::default();
+        metrics.record(Event::EntriesApplied {
+            source: ChangeSource::Subscribe,
+            count: 3,
+        });
+        metrics.record(Event::FetchSucceeded {
+            kind: crate::FetchKind::Initial,
+            attempt: 1,
+            entries: 2,
+            elapsed: Duration::from_millis(30),
+        });
+        let status = Status {
+            reconnects: 2,
+            ..Status::default()
+        };
+
+        let text = metrics.render(5, &status);
+
+        for line in [
+            "# TYPE streamed_cache_keys gauge",
+            "streamed_cache_keys 5",
+            "streamed_cache_updates_applied_total{source=\"fetch\"} 0",
+            "streamed_cache_updates_applied_total{source=\"subscribe\"} 3",
+            "streamed_cache_updates_applied_total{source=\"expire\"} 0",
+            "streamed_cache_reconnects_total 2",
+            "# TYPE streamed_cache_fetch_duration_seconds histogram",
+            "streamed_cache_fetch_duration_seconds_bucket{le=\"0.025\"} 0",
+            "streamed_cache_fetch_duration_seconds_bucket{le=\"0.05\"} 1",
+            "streamed_cache_fetch_duration_seconds_bucket{le=\"+Inf\"} 1",
+            "streamed_cache_fetch_duration_seconds_sum 0.03",
+            "streamed_cache_fetch_duration_seconds_count 1",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "missing {:?} in\n{}",
+                line,
+                text
+            );
+        }
+        assert!(!text.contains("seconds_since_last_update"));
+    }
+}
@@ -0,0 +1,103 @@
+use std::time::Duration;
+
+use crate::Backoff;
+
+/// Decides whether and when a failed `fetch` is attempted again.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one.
+    pub max_attempts: u32,
+    pub backoff: Backoff,
+    /// Overall time budget for all attempts, measured from the first one.
+    /// An attempt still running when the deadline passes is abandoned.
+    pub deadline: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            backoff: Backoff::default(),
+            deadline: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A single attempt without any retries.
+    pub fn never() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Self::default()
+        }
+    }
+
+    /// Time left before the deadline, or `None` if there is no deadline.
+    pub fn remaining(&self, elapsed: Duration) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_sub(elapsed))
+    }
+
+    /// Delay before the next attempt after `attempts` attempts have failed,
+    /// or `None` if we should give up.
+    pub fn next_delay(&self, attempts: u32, elapsed: Duration) -> Option<Duration> {
+        if attempts >= self.max_attempts {
+            return None;
+        }
+        let delay = self.backoff.delay(attempts.saturating_sub(1));
+        match self.deadline {
+            Some(deadline) if elapsed + delay >= deadline => None,
+            _ => Some(delay),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(max_attempts: u32, deadline: Option<Duration>) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            backoff: Backoff {
+                initial: Duration::from_millis(10),
+                max: Duration::from_secs(1),
+                multiplier: 2.0,
+                jitter: 0.0,
+            },
+            deadline,
+        }
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let policy = policy(3, None);
+
+        assert_eq!(
+            policy.next_delay(1, Duration::ZERO),
+            Some(Duration::from_millis(10))
+        );
+        assert_eq!(
+            policy.next_delay(2, Duration::ZERO),
+            Some(Duration::from_millis(20))
+        );
+        assert_eq!(policy.next_delay(3, Duration::ZERO), None);
+        assert_eq!(RetryPolicy::never().next_delay(1, Duration::ZERO), None);
+    }
+
+    #[test]
+    fn gives_up_when_retry_would_pass_deadline() {
+        let policy = policy(10, Some(Duration::from_millis(100)));
+
+        assert!(policy.next_delay(1, Duration::from_millis(50)).is_some());
+        assert_eq!(policy.next_delay(1, Duration::from_millis(95)), None);
+        assert_eq!(
+            policy.remaining(Duration::from_millis(30)),
+            Some(Duration::from_millis(70))
+        );
+        assert_eq!(
+            policy.remaining(Duration::from_millis(300)),
+            Some(Duration::ZERO)
+        );
+    }
+}
@@ -0,0 +1,71 @@
+use std::{borrow::Borrow, collections::HashMap, hash::Hash, sync::Arc, time::Instant};
+
+use crate::Entry;
+
+/// Immutable view of the whole cache at one point in time, as returned by
+/// [`StreamCache::snapshot`](crate::StreamCache::snapshot).
+///
+/// It is taken while no update is being applied, so it never contains part
+/// of an update batch such as a fetched snapshot. Clones share the same data.
+#[derive(Debug)]
+pub struct CacheSnapshot<K, V> {
+    entries: Arc<HashMap<K, Entry<V>>>,
+    taken_at: Instant,
+}
+
+impl<K, V> Clone for CacheSnapshot<K, V> {
+    fn clone(&self) -> Self {
+        Self {
+            entries: self.entries.clone(),
+            taken_at: self.taken_at,
+        }
+    }
+}
+
+impl<K: Hash + Eq, V> CacheSnapshot<K, V> {
+    pub(crate) fn new(entries: HashMap<K, Entry<V>>) -> Self {
+        Self {
+            entries: Arc::new(entries),
+            taken_at: Instant::now(),
+        }
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.entries.get(key).map(|entry| &entry.value)
+    }
+
+    /// The value for `key` with metadata about its last update.
+    pub fn get_entry<Q>(&self, key: &Q) -> Option<&Entry<V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.entries.get(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Iterates over all keys and values in arbitrary order.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.entries.iter().map(|(key, entry)| (key, &entry.value))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.entries.keys()
+    }
+
+    /// When the snapshot was taken.
+    pub fn taken_at(&self) -> Instant {
+        self.taken_at
+    }
+}
@@ -0,0 +1,77 @@
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use std::collections::HashMap;
+
+use crate::{Api, ApiError, Delta, Version, VersionedApi};
+
+/// A value as delivered by upstream, or `None` if the key was deleted, with
+/// its version if the API has one.
+pub(crate) type Update<K, V> = (K, Option<V>, Option<Version>);
+pub(crate) type Snapshot<K, V> = HashMap<K, (V, Option<Version>)>;
+
+/// Common view of [`Api`] and [`VersionedApi`] used by the background tasks.
+#[async_trait]
+pub(crate) trait Source<K, V>: Send + Sync + 'static {
+    async fn fetch(&self) -> Result<Snapshot<K, V>, ApiError>;
+    async fn subscribe(&self) -> BoxStream<Result<Update<K, V>, ApiError>>;
+}
+
+fn into_update<K, V>(delta: Delta<K, V>, version: Option<Version>) -> Update<K, V> {
+    match delta {
+        Delta::Upsert(key, value) => (key, Some(value), version),
+        Delta::Delete(key) => (key, None, version),
+    }
+}
+
+pub(crate) struct Unversioned<A>(pub A);
+
+#[async_trait]
+impl<K, V, A> Source<K, V> for Unversioned<A>
+where
+    K: std::hash::Hash + Eq + Send + 'static,
+    V: Send + 'static,
+    A: Api<K, V>,
+{
+    async fn fetch(&self) -> Result<Snapshot<K, V>, ApiError> {
+        let snapshot = self.0.fetch().await?;
+        Ok(snapshot
+            .into_iter()
+            .map(|(key, value)| (key, (value, None)))
+            .collect())
+    }
+
+    async fn subscribe(&self) -> BoxStream<Result<Update<K, V>, ApiError>> {
+        self.0
+            .subscribe()
+            .await
+            .map(|delta| delta.map(|delta| into_update(delta, None)))
+            .boxed()
+    }
+}
+
+pub(crate) struct Versioned<A>(pub A);
+
+#[async_trait]
+impl<K, V, A> Source<K, V> for Versioned<A>
+where
+    K: std::hash::Hash + Eq + Send + 'static,
+    V: Send + 'static,
+    A: VersionedApi<K, V>,
+{
+    async fn fetch(&self) -> Result<Snapshot<K, V>, ApiError> {
+        let snapshot = self.0.fetch().await?;
+        Ok(snapshot
+            .into_iter()
+            .map(|(key, (value, version))| (key, (value, Some(version))))
+            .collect())
+    }
+
+    async fn subscribe(&self) -> BoxStream<Result<Update<K, V>, ApiError>> {
+        self.0
+            .subscribe()
+            .await
+            .map(|delta| delta.map(|(delta, version)| into_update(delta, Some(version))))
+            .boxed()
+    }
+}
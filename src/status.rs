@@ -0,0 +1,75 @@
+use std::time::Instant;
+
+use crate::ApiError;
+
+/// Progress of the initial `fetch` that populates the cache.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FetchOutcome {
+    /// The first attempt has not completed yet.
+    Pending,
+    /// An attempt failed and another one is scheduled.
+    Retrying { attempts: u32, last_error: ApiError },
+    /// The fetched values have been applied to the cache.
+    Succeeded { attempts: u32 },
+    /// The retry policy was exhausted without a successful attempt.
+    Failed { attempts: u32, last_error: ApiError },
+}
+
+/// State of the `subscribe` stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubscriptionState {
+    /// Waiting for `subscribe` to return a stream.
+    Connecting,
+    /// The stream is open and updates are being applied.
+    Streaming,
+    /// The stream ended and a resubscribe is scheduled.
+    Ended,
+    /// The stream delivered an error that retrying cannot fix, so the cache
+    /// stopped resubscribing. See [`Status::last_stream_error`].
+    Failed,
+}
+
+/// Health of the background tasks that keep the cache up to date.
+#[derive(Debug, Clone)]
+pub struct Status {
+    /// Outcome of the initial `fetch`, or of the latest successful one.
+    pub fetch: FetchOutcome,
+    pub subscription: SubscriptionState,
+    /// When a value was last applied to the cache, by any source.
+    pub last_update: Option<Instant>,
+    /// Failed `fetch` attempts of any kind.
+    pub fetch_errors: u64,
+    pub last_fetch_error: Option<ApiError>,
+    /// Errors delivered as items of the `subscribe` stream.
+    pub stream_errors: u64,
+    pub last_stream_error: Option<ApiError>,
+    /// Number of resubscribes after the `subscribe` stream ended.
+    pub reconnects: u64,
+}
+
+impl Default for Status {
+    fn default() -> Self {
+        Self {
+            fetch: FetchOutcome::Pending,
+            subscription: SubscriptionState::Connecting,
+            last_update: None,
+            fetch_errors: 0,
+            last_fetch_error: None,
+            stream_errors: 0,
+            last_stream_error: None,
+            reconnects: 0,
+        }
+    }
+}
+
+impl Status {
+    pub(crate) fn record_fetch_error(&mut self, error: &ApiError) {
+        self.fetch_errors += 1;
+        self.last_fetch_error = Some(error.clone());
+    }
+
+    pub(crate) fn record_stream_error(&mut self, error: &ApiError) {
+        self.stream_errors += 1;
+        self.last_stream_error = Some(error.clone());
+    }
+}
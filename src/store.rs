@@ -0,0 +1,856 @@
+use std::{
+    borrow::Borrow,
+    collections::HashMap,
+    hash::Hash,
+    ops::RangeBounds,
+    sync::{Mutex, MutexGuard},
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{
+    aggregate::{Aggregate, Aggregates, Numeric},
+    history::{History, HistoryLimit, Sample},
+    index::Index,
+    source::{Snapshot, Update},
+    Change, ChangeSource, Version,
+};
+
+/// The cached values, each stamped with the sequence number of the write that
+/// produced it so that snapshots can be merged without losing newer updates.
+/// Deleted keys leave a tombstone behind for the same reason while a fetch is
+/// in flight, which is reclaimed once no fetch is in flight anymore. Versioned
+/// deletions always do, to reject older versions arriving late, and are only
+/// dropped by the next merged snapshot, so they pile up between fetches.
+///
+/// Reads and writes share a single mutex. Writers hold it through
+/// [`Store::write`] until their changes are published, which keeps the
+/// sequence numbers and the order in which changes are published consistent.
+pub(crate) struct Store<K, V> {
+    state: Mutex<State<K, V>>,
+    /// Entries not updated within this long are treated as absent.
+    ttl: Option<Duration>,
+    /// How many past values are kept per key, none if `None`.
+    history: Option<HistoryLimit>,
+}
+
+struct State<K, V> {
+    values: HashMap<K, Slot<V>>,
+    /// Sequence number of the most recent write.
+    seq: u64,
+    /// Sequence numbers at which the fetches in flight were issued.
+    fetches: Vec<u64>,
+    /// Sequence number at which the newest merged snapshot was issued.
+    merged: u64,
+    derived: Derived<K, V>,
+}
+
+/// State derived from the values, tracked once it was first queried.
+struct Derived<K, V> {
+    /// Aggregates over all values.
+    aggregates: Option<Aggregates<V>>,
+    /// Keys ordered by value.
+    index: Option<Index<K, V>>,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> Derived<K, V> {
+    /// Updates the aggregates and index, if tracked, for a value of `key`
+    /// replaced by another.
+    fn track(&mut self, key: &K, old: Option<&V>, new: Option<&V>) {
+        if let Some(aggregates) = &mut self.aggregates {
+            if let Some(old) = old {
+                aggregates.remove(old);
+            }
+            if let Some(new) = new {
+                aggregates.add(new);
+            }
+        }
+        if let Some(index) = &mut self.index {
+            if let Some(old) = old {
+                index.remove(key, old);
+            }
+            if let Some(new) = new {
+                index.add(key, new);
+            }
+        }
+    }
+}
+
+/// Exclusive access to a [`Store`] for writing. Changes returned by its
+/// methods should be published before it is dropped, so that they are seen
+/// in the order they were applied.
+pub(crate) struct StoreWriter<'a, K, V> {
+    store: &'a Store<K, V>,
+    state: MutexGuard<'a, State<K, V>>,
+}
+
+#[derive(Debug)]
+struct Slot<V> {
+    /// `None` once the key was deleted.
+    entry: Option<Entry<V>>,
+    /// Version of the last write, be it a value or a deletion.
+    version: Option<Version>,
+    seq: u64,
+    /// Past values including the current one, cleared on deletion.
+    history: History<V>,
+}
+
+/// A cached value together with metadata about its last update.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry<V> {
+    pub value: V,
+    /// Upstream version, if the value came from a [`VersionedApi`](crate::VersionedApi).
+    pub version: Option<Version>,
+    /// Whether the last update came from `fetch` or `subscribe`.
+    pub source: ChangeSource,
+    pub updated_at: Instant,
+    /// Wall-clock time of the last update, for display and logging.
+    pub updated_wall_time: SystemTime,
+    /// Number of updates applied to this key, including the first one.
+    pub update_count: u64,
+}
+
+impl<V> Entry<V> {
+    /// Time since the last update.
+    pub fn age(&self) -> Duration {
+        self.updated_at.elapsed()
+    }
+}
+
+/// Whether a value with version `new` may replace one with version `current`.
+/// Versions are only compared when both sides have one; otherwise the later
+/// write wins.
+pub(crate) fn supersedes(new: Option<Version>, current: Option<Version>) -> bool {
+    match (new, current) {
+        (Some(new), Some(current)) => new > current,
+        _ => true,
+    }
+}
+
+impl<K, V> Default for Store<K, V> {
+    fn default() -> Self {
+        Self::new(None, None)
+    }
+}
+
+impl<K, V> Store<K, V> {
+    pub fn new(ttl: Option<Duration>, history: Option<HistoryLimit>) -> Self {
+        Self {
+            state: Mutex::new(State {
+                values: HashMap::new(),
+                seq: 0,
+                fetches: Vec::new(),
+                merged: 0,
+                derived: Derived {
+                    aggregates: None,
+                    index: None,
+                },
+            }),
+            ttl,
+            history,
+        }
+    }
+
+    fn is_live(&self, entry: &Entry<V>) -> bool {
+        self.ttl.is_none_or(|ttl| entry.age() <= ttl)
+    }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> Store<K, V> {
+    /// The live entry for `key` in `values`. Expired entries are only removed
+    /// by [`StoreWriter::expire`], so reads must skip them.
+    fn live<'a, Q>(&self, values: &'a HashMap<K, Slot<V>>, key: &Q) -> Option<&'a Entry<V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        values
+            .get(key)
+            .and_then(|slot| slot.entry.as_ref())
+            .filter(|entry| self.is_live(entry))
+    }
+
+    fn read<Q, T>(&self, key: &Q, f: impl FnOnce(&Entry<V>) -> T) -> Option<T>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let state = self.state.lock().expect("poisoned");
+        self.live(&state.values, key).map(f)
+    }
+
+    /// Runs `read` on derived state such as the aggregates. Tracking starts
+    /// on first use, so caches that never query it don't pay for it: `empty`
+    /// is filled with all values first.
+    fn tracked<T, R>(
+        &self,
+        field: fn(&mut Derived<K, V>) -> &mut Option<T>,
+        mut empty: T,
+        add: impl Fn(&mut T, &K, &V),
+        read: impl FnOnce(&T) -> R,
+    ) -> R {
+        let mut state = self.state.lock().expect("poisoned");
+        let State {
+            values, derived, ..
+        } = &mut *state;
+        let tracked = field(derived).get_or_insert_with(|| {
+            for (key, slot) in values.iter() {
+                if let Some(entry) = &slot.entry {
+                    add(&mut empty, key, &entry.value);
+                }
+            }
+            empty
+        });
+        read(tracked)
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.read(key, |entry| entry.value.clone())
+    }
+
+    pub fn get_entry<Q>(&self, key: &Q) -> Option<Entry<V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.read(key, Entry::clone)
+    }
+
+    /// Past values of `key`, oldest first. Empty unless history is enabled.
+    pub fn history<Q>(&self, key: &Q) -> Vec<Sample<V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let Some(limit) = self.history else {
+            return Vec::new();
+        };
+        let state = self.state.lock().expect("poisoned");
+        state
+            .values
+            .get(key)
+            .map_or_else(Vec::new, |slot| slot.history.samples(limit))
+    }
+
+    /// The value `key` had at `at`, if that is still within its history.
+    pub fn value_at<Q>(&self, key: &Q, at: Instant) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let limit = self.history?;
+        let state = self.state.lock().expect("poisoned");
+        state.values.get(key)?.history.value_at(at, limit)
+    }
+
+    /// Number of cached keys that have not outlived the TTL.
+    pub fn len(&self) -> usize {
+        let state = self.state.lock().expect("poisoned");
+        state
+            .values
+            .values()
+            .filter_map(|slot| slot.entry.as_ref())
+            .filter(|entry| self.is_live(entry))
+            .count()
+    }
+
+    /// Copies all live entries, as they were between two writes.
+    pub fn snapshot(&self) -> HashMap<K, Entry<V>> {
+        let state = self.state.lock().expect("poisoned");
+        state
+            .values
+            .iter()
+            .filter_map(|(key, slot)| Some((key, slot.entry.as_ref()?)))
+            .filter(|(_, entry)| self.is_live(entry))
+            .map(|(key, entry)| (key.clone(), entry.clone()))
+            .collect()
+    }
+
+    /// Aggregates over all values, `None` if there are none. Expired values
+    /// are included until [`StoreWriter::expire`] removes them.
+    pub fn aggregate(&self) -> Option<Aggregate>
+    where
+        V: Numeric,
+    {
+        self.tracked(
+            |derived| &mut derived.aggregates,
+            Aggregates::new(V::to_f64),
+            |aggregates, _, value| aggregates.add(value),
+            Aggregates::summary,
+        )
+    }
+
+    /// Keys with a value in `range`, ordered by value. Like the aggregates,
+    /// this includes expired values until they are removed.
+    pub fn range(&self, range: impl RangeBounds<V>) -> Vec<(K, V)>
+    where
+        V: Numeric,
+    {
+        self.tracked(
+            |derived| &mut derived.index,
+            Index::new(V::to_f64),
+            Index::add,
+            |index| index.range(range),
+        )
+    }
+
+    /// The `n` keys with the highest values, highest first.
+    pub fn top_n(&self, n: usize) -> Vec<(K, V)>
+    where
+        V: Numeric,
+    {
+        self.tracked(
+            |derived| &mut derived.index,
+            Index::new(V::to_f64),
+            Index::add,
+            |index| index.top_n(n),
+        )
+    }
+
+    /// Sequence number of the most recent write, to stamp streamed updates
+    /// with when they are received.
+    pub fn seq(&self) -> u64 {
+        self.state.lock().expect("poisoned").seq
+    }
+
+    /// Waits for readers and other writers to finish and returns exclusive
+    /// write access.
+    pub fn write(&self) -> StoreWriter<'_, K, V> {
+        StoreWriter {
+            store: self,
+            state: self.state.lock().expect("poisoned"),
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone + PartialEq> StoreWriter<'_, K, V> {
+    /// The cached value for `key`, for reading while writers are held off.
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.store
+            .live(&self.state.values, key)
+            .map(|entry| entry.value.clone())
+    }
+
+    /// Whether a snapshot was merged that was issued after an update stamped
+    /// with `received` by [`Store::seq`]. The snapshot is newer than such an
+    /// update, which must not be applied anymore if it was held back.
+    pub fn merged_since(&self, received: u64) -> bool {
+        self.state.merged > received
+    }
+
+    /// Removes all entries that have outlived the TTL and returns their
+    /// deletions.
+    pub fn expire(&mut self) -> Vec<Change<K, V>> {
+        let Some(ttl) = self.store.ttl else {
+            return Vec::new();
+        };
+        let State {
+            values, derived, ..
+        } = &mut *self.state;
+        let mut expired = Vec::new();
+        values.retain(|key, slot| {
+            let Some(entry) = &slot.entry else {
+                return true;
+            };
+            let live = entry.age() <= ttl;
+            if !live {
+                derived.track(key, Some(&entry.value), None);
+                expired.push(Change {
+                    key: key.clone(),
+                    old: Some(entry.value.clone()),
+                    new: None,
+                    source: ChangeSource::Expire,
+                });
+            }
+            live
+        });
+        expired
+    }
+
+    /// Registers a `fetch` about to be issued and returns the sequence number
+    /// to pass to [`StoreWriter::merge_snapshot`] once it returns, or to
+    /// [`StoreWriter::end_fetch`] if it fails.
+    pub fn issue_fetch(&mut self) -> u64 {
+        // take a fresh number, so that updates received before and after are
+        // stamped apart, see [`StoreWriter::merged_since`]
+        self.state.seq += 1;
+        let issued_at = self.state.seq;
+        self.state.fetches.push(issued_at);
+        issued_at
+    }
+
+    /// Unregisters the `fetch` issued at `issued_at`, for when it failed;
+    /// [`StoreWriter::merge_snapshot`] does so itself.
+    pub fn end_fetch(&mut self, issued_at: u64) {
+        if let Some(index) = self.state.fetches.iter().position(|seq| *seq == issued_at) {
+            self.state.fetches.swap_remove(index);
+        }
+        if self.state.fetches.is_empty() {
+            self.reclaim_tombstones();
+        }
+    }
+
+    /// Drops unversioned tombstones, which only guard against snapshots of
+    /// fetches in flight.
+    fn reclaim_tombstones(&mut self) {
+        self.state
+            .values
+            .retain(|_, slot| slot.entry.is_some() || slot.version.is_some());
+    }
+
+    /// Applies an update or deletion unless the cached key has a newer
+    /// version. Returns the change if the cached value changed.
+    ///
+    /// An update to the value already cached only re-stamps the entry, so
+    /// that it does not expire, and is not counted as a change.
+    pub fn insert(
+        &mut self,
+        (key, value, version): Update<K, V>,
+        source: ChangeSource,
+    ) -> Option<Change<K, V>> {
+        let State {
+            values,
+            seq,
+            fetches,
+            derived,
+            ..
+        } = &mut *self.state;
+        if !supersedes(version, values.get(&key).and_then(|slot| slot.version)) {
+            return None;
+        }
+        if let (Some(value), Some(slot)) = (&value, values.get_mut(&key)) {
+            if let Some(entry) = slot.entry.as_mut().filter(|entry| entry.value == *value) {
+                *seq += 1;
+                slot.seq = *seq;
+                slot.version = version;
+                entry.version = version;
+                entry.updated_at = Instant::now();
+                entry.updated_wall_time = SystemTime::now();
+                return None;
+            }
+        }
+        if value.is_none() && version.is_none() && fetches.is_empty() {
+            // no snapshot can resurrect the key, so no tombstone is needed
+            let old = values.remove(&key).and_then(|slot| slot.entry)?.value;
+            *seq += 1;
+            derived.track(&key, Some(&old), None);
+            return Some(Change {
+                key,
+                old: Some(old),
+                new: None,
+                source,
+            });
+        }
+        *seq += 1;
+        let update_count = values
+            .get(&key)
+            .and_then(|slot| slot.entry.as_ref())
+            .map_or(0, |entry| entry.update_count);
+        let entry = value.clone().map(|value| Entry {
+            value,
+            version,
+            source,
+            updated_at: Instant::now(),
+            updated_wall_time: SystemTime::now(),
+            update_count: update_count + 1,
+        });
+        let mut history = History::default();
+        if let (Some(limit), Some(entry)) = (self.store.history, &entry) {
+            if let Some(slot) = values.get_mut(&key) {
+                history = std::mem::take(&mut slot.history);
+            }
+            history.push(
+                Sample {
+                    value: entry.value.clone(),
+                    at: entry.updated_at,
+                    wall_time: entry.updated_wall_time,
+                },
+                limit,
+            );
+        }
+        let slot = Slot {
+            entry,
+            version,
+            seq: *seq,
+            history,
+        };
+        let old = values
+            .insert(key.clone(), slot)
+            .and_then(|slot| slot.entry)
+            .map(|entry| entry.value);
+        if old.is_none() && value.is_none() {
+            // deleted a key that was not cached
+            return None;
+        }
+        derived.track(&key, old.as_ref(), value.as_ref());
+        Some(Change {
+            key,
+            old,
+            new: value,
+            source,
+        })
+    }
+
+    /// Applies a full snapshot that was requested when the store was at
+    /// sequence `issued_at`.
+    ///
+    /// Keys missing from the snapshot were deleted upstream and are removed,
+    /// unless they were written after `issued_at`. Versioned values are
+    /// resolved by version alone. Unversioned keys written after `issued_at`
+    /// have seen an update or deletion that may be newer than the snapshot,
+    /// so they keep their current state.
+    pub fn merge_snapshot(
+        &mut self,
+        snapshot: Snapshot<K, V>,
+        issued_at: u64,
+    ) -> Vec<Change<K, V>> {
+        let mut changes = Vec::new();
+        let State {
+            values,
+            seq,
+            fetches,
+            merged,
+            derived,
+        } = &mut *self.state;
+        *merged = (*merged).max(issued_at);
+        // other fetches were issued before or after this one
+        let overlapping = fetches.len() > 1;
+        values.retain(|key, slot| {
+            if slot.seq > issued_at || snapshot.contains_key(key) {
+                return true;
+            }
+            let entry = slot.entry.take();
+            derived.track(key, entry.as_ref().map(|entry| &entry.value), None);
+            changes.extend(entry.map(|entry| Change {
+                key: key.clone(),
+                old: Some(entry.value),
+                new: None,
+                source: ChangeSource::Fetch,
+            }));
+            if !overlapping {
+                // tombstones are dropped too, the snapshot is newer than them
+                return false;
+            }
+            // keep a tombstone newer than any fetch in flight, so that an
+            // older snapshot does not bring the key back
+            *seq += 1;
+            slot.seq = *seq;
+            true
+        });
+        for (key, (value, version)) in snapshot {
+            let newer = self.state.values.get(&key).is_some_and(|slot| {
+                slot.version.is_none() && version.is_none() && slot.seq > issued_at
+            });
+            if !newer {
+                changes.extend(self.insert((key, Some(value), version), ChangeSource::Fetch));
+            }
+        }
+        self.end_fetch(issued_at);
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use maplit::hashmap;
+
+    use super::*;
+    use crate::{Temperature, Unit};
+
+    #[test]
+    fn snapshot_does_not_overwrite_newer_updates() {
+        let store: Store<String, u64> = Store::default();
+        store.write().insert(
+            ("Berlin".to_string(), Some(20), None),
+            ChangeSource::Subscribe,
+        );
+        store.write().insert(
+            ("Paris".to_string(), Some(25), None),
+            ChangeSource::Subscribe,
+        );
+
+        let issued_at = store.write().issue_fetch();
+        store.write().insert(
+            ("Paris".to_string(), Some(27), None),
+            ChangeSource::Subscribe,
+        );
+
+        store.write().merge_snapshot(
+            hashmap! {
+                "Berlin".to_string() => (21, None),
+                "Paris".to_string() => (26, None),
+                "Rome".to_string() => (30, None),
+            },
+            issued_at,
+        );
+
+        assert_eq!(store.get("Berlin"), Some(21));
+        assert_eq!(store.get("Paris"), Some(27));
+        assert_eq!(store.get("Rome"), Some(30));
+    }
+
+    #[test]
+    fn last_writer_wins_by_version() {
+        let store: Store<String, u64> = Store::default();
+        let insert = |value, version| {
+            store
+                .write()
+                .insert(
+                    ("Berlin".to_string(), Some(value), Some(version)),
+                    ChangeSource::Subscribe,
+                )
+                .is_some()
+        };
+        assert!(insert(20, 5));
+        assert!(!insert(19, 4));
+        assert!(!insert(19, 5));
+        assert_eq!(store.get("Berlin"), Some(20));
+
+        let issued_at = store.write().issue_fetch();
+        store.write().insert(
+            ("Berlin".to_string(), Some(22), Some(7)),
+            ChangeSource::Subscribe,
+        );
+        store.write().merge_snapshot(
+            hashmap! { "Berlin".to_string() => (21, Some(6)) },
+            issued_at,
+        );
+        assert_eq!(store.get("Berlin"), Some(22));
+
+        store.write().merge_snapshot(
+            hashmap! { "Berlin".to_string() => (23, Some(8)) },
+            issued_at,
+        );
+        assert_eq!(store.get("Berlin"), Some(23));
+    }
+
+    #[test]
+    fn tracks_entry_metadata() {
+        let store: Store<String, u64> = Store::default();
+        store
+            .write()
+            .insert(("Berlin".to_string(), Some(20), None), ChangeSource::Fetch);
+        let first = store.get_entry("Berlin").unwrap();
+        assert_eq!(first.source, ChangeSource::Fetch);
+        assert_eq!(first.update_count, 1);
+
+        store.write().insert(
+            ("Berlin".to_string(), Some(21), Some(3)),
+            ChangeSource::Subscribe,
+        );
+        let second = store.get_entry("Berlin").unwrap();
+        assert_eq!(second.value, 21);
+        assert_eq!(second.version, Some(3));
+        assert_eq!(second.source, ChangeSource::Subscribe);
+        assert_eq!(second.update_count, 2);
+        assert!(second.updated_at >= first.updated_at);
+    }
+
+    #[test]
+    fn records_history_only_for_changed_values() {
+        let store: Store<String, u64> = Store::new(None, Some(HistoryLimit::Len(2)));
+        for value in [20, 21, 21, 21] {
+            store.write().insert(
+                ("Berlin".to_string(), Some(value), None),
+                ChangeSource::Fetch,
+            );
+        }
+
+        let values: Vec<_> = store
+            .history("Berlin")
+            .into_iter()
+            .map(|sample| sample.value)
+            .collect();
+        assert_eq!(values, vec![20, 21]);
+    }
+
+    #[test]
+    fn applies_a_value_in_another_unit() {
+        let store: Store<String, Temperature> = Store::default();
+        store.write().insert(
+            ("Oslo".to_string(), Some(Temperature::celsius(0.0)), None),
+            ChangeSource::Fetch,
+        );
+
+        let change = store.write().insert(
+            (
+                "Oslo".to_string(),
+                Some(Temperature::fahrenheit(32.0)),
+                None,
+            ),
+            ChangeSource::Subscribe,
+        );
+
+        assert!(change.is_some());
+        assert_eq!(
+            store.get("Oslo").map(Temperature::unit),
+            Some(Unit::Fahrenheit)
+        );
+    }
+
+    #[test]
+    fn expires_entries_after_ttl() {
+        let store: Store<String, u64> = Store::new(Some(Duration::from_millis(20)), None);
+        store
+            .write()
+            .insert(("Berlin".to_string(), Some(20), None), ChangeSource::Fetch);
+        assert_eq!(store.get("Berlin"), Some(20));
+        assert!(store.write().expire().is_empty());
+
+        std::thread::sleep(Duration::from_millis(30));
+        store.write().insert(
+            ("Paris".to_string(), Some(25), None),
+            ChangeSource::Subscribe,
+        );
+
+        assert_eq!(store.get("Berlin"), None);
+        assert_eq!(store.get("Paris"), Some(25));
+        assert_eq!(
+            store.write().expire(),
+            vec![Change {
+                key: "Berlin".to_string(),
+                old: Some(20),
+                new: None,
+                source: ChangeSource::Expire,
+            }]
+        );
+    }
+
+    #[test]
+    fn snapshot_copies_live_entries() {
+        let store: Store<String, u64> = Store::default();
+        store
+            .write()
+            .insert(("Berlin".to_string(), Some(20), None), ChangeSource::Fetch);
+        let snapshot = store.snapshot();
+
+        store.write().insert(
+            ("Berlin".to_string(), Some(21), None),
+            ChangeSource::Subscribe,
+        );
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot["Berlin"].value, 20);
+        assert_eq!(store.snapshot()["Berlin"].value, 21);
+    }
+
+    #[test]
+    fn deletions_survive_older_snapshots() {
+        let store: Store<String, u64> = Store::default();
+        store
+            .write()
+            .insert(("Berlin".to_string(), Some(20), None), ChangeSource::Fetch);
+        store
+            .write()
+            .insert(("Paris".to_string(), Some(25), None), ChangeSource::Fetch);
+
+        let issued_at = store.write().issue_fetch();
+        let deleted = store
+            .write()
+            .insert(("Paris".to_string(), None, None), ChangeSource::Subscribe);
+        assert_eq!(deleted.and_then(|change| change.old), Some(25));
+
+        let changes = store.write().merge_snapshot(
+            hashmap! {
+                "Paris".to_string() => (25, None),
+                "Rome".to_string() => (30, None),
+            },
+            issued_at,
+        );
+        assert_eq!(changes.len(), 2);
+        assert_eq!(store.get("Berlin"), None);
+        assert_eq!(store.get("Paris"), None);
+        assert_eq!(store.get("Rome"), Some(30));
+
+        let issued_at = store.write().issue_fetch();
+        store
+            .write()
+            .merge_snapshot(hashmap! { "Rome".to_string() => (30, None) }, issued_at);
+        assert_eq!(store.snapshot().len(), 1);
+        assert!(store
+            .state
+            .lock()
+            .unwrap()
+            .values
+            .keys()
+            .all(|key| key == "Rome"));
+    }
+
+    #[test]
+    fn older_overlapping_snapshot_does_not_restore_deleted_keys() {
+        let store: Store<String, u64> = Store::default();
+        store
+            .write()
+            .insert(("Riga".to_string(), Some(20), None), ChangeSource::Fetch);
+
+        let older = store.write().issue_fetch();
+        store
+            .write()
+            .insert(("Oslo".to_string(), Some(5), None), ChangeSource::Subscribe);
+        let newer = store.write().issue_fetch();
+        let changes = store
+            .write()
+            .merge_snapshot(hashmap! { "Oslo".to_string() => (5, None) }, newer);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(store.get("Riga"), None);
+
+        let changes = store.write().merge_snapshot(
+            hashmap! {
+                "Oslo".to_string() => (5, None),
+                "Riga".to_string() => (20, None),
+            },
+            older,
+        );
+        assert!(changes.is_empty());
+        assert_eq!(store.get("Riga"), None);
+        assert_eq!(store.snapshot().len(), 1);
+        assert!(store
+            .state
+            .lock()
+            .unwrap()
+            .values
+            .keys()
+            .all(|key| key == "Oslo"));
+    }
+
+    #[test]
+    fn keeps_tombstones_only_while_fetching() {
+        let store: Store<String, u64> = Store::default();
+        let slots = || store.state.lock().unwrap().values.len();
+        store
+            .write()
+            .insert(("Berlin".to_string(), Some(20), None), ChangeSource::Fetch);
+        for key in ["Berlin", "Paris", "Rome"] {
+            store
+                .write()
+                .insert((key.to_string(), None, None), ChangeSource::Subscribe);
+        }
+        assert_eq!(slots(), 0);
+
+        let issued_at = store.write().issue_fetch();
+        store
+            .write()
+            .insert(("Paris".to_string(), None, None), ChangeSource::Subscribe);
+        assert_eq!(slots(), 1);
+        store.write().end_fetch(issued_at);
+        assert_eq!(slots(), 0);
+
+        store
+            .write()
+            .insert(("Rome".to_string(), None, Some(3)), ChangeSource::Subscribe);
+        assert!(store
+            .write()
+            .insert(
+                ("Rome".to_string(), Some(30), Some(2)),
+                ChangeSource::Subscribe
+            )
+            .is_none());
+        assert_eq!(store.get("Rome"), None);
+    }
+}
@@ -0,0 +1,163 @@
+use std::{cmp::Ordering, fmt};
+
+/// Scale a [`Temperature`] is measured in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Unit {
+    Celsius,
+    Fahrenheit,
+    Kelvin,
+}
+
+impl Unit {
+    pub fn symbol(self) -> &'static str {
+        match self {
+            Unit::Celsius => "°C",
+            Unit::Fahrenheit => "°F",
+            Unit::Kelvin => "K",
+        }
+    }
+}
+
+/// A measured temperature in the unit upstream reported it in.
+///
+/// Two temperatures are equal if they have the same unit and value, so
+/// `0 °C != 32 °F`; use [`Temperature::is_equivalent`] to compare across
+/// units. Temperatures are ordered by their value in kelvin, and ones that
+/// convert to the same kelvin by unit and then value, which keeps the order
+/// consistent with equality. As with any `f64`, conversions may be off by a
+/// rounding error.
+#[derive(Debug, Clone, Copy)]
+pub struct Temperature {
+    value: f64,
+    unit: Unit,
+}
+
+impl Temperature {
+    pub fn new(value: f64, unit: Unit) -> Self {
+        Self { value, unit }
+    }
+
+    pub fn celsius(value: f64) -> Self {
+        Self::new(value, Unit::Celsius)
+    }
+
+    pub fn fahrenheit(value: f64) -> Self {
+        Self::new(value, Unit::Fahrenheit)
+    }
+
+    pub fn kelvin(value: f64) -> Self {
+        Self::new(value, Unit::Kelvin)
+    }
+
+    /// The value in [`Temperature::unit`].
+    pub fn value(self) -> f64 {
+        self.value
+    }
+
+    pub fn unit(self) -> Unit {
+        self.unit
+    }
+
+    /// The same temperature expressed in `unit`.
+    pub fn to(self, unit: Unit) -> Self {
+        Self::new(self.in_unit(unit), unit)
+    }
+
+    /// The value converted to `unit`.
+    pub fn in_unit(self, unit: Unit) -> f64 {
+        if unit == self.unit {
+            return self.value;
+        }
+        let kelvin = match self.unit {
+            Unit::Celsius => self.value + 273.15,
+            Unit::Fahrenheit => (self.value - 32.0) * 5.0 / 9.0 + 273.15,
+            Unit::Kelvin => self.value,
+        };
+        match unit {
+            Unit::Celsius => kelvin - 273.15,
+            Unit::Fahrenheit => (kelvin - 273.15) * 9.0 / 5.0 + 32.0,
+            Unit::Kelvin => kelvin,
+        }
+    }
+
+    pub fn as_celsius(self) -> f64 {
+        self.in_unit(Unit::Celsius)
+    }
+
+    pub fn as_fahrenheit(self) -> f64 {
+        self.in_unit(Unit::Fahrenheit)
+    }
+
+    pub fn as_kelvin(self) -> f64 {
+        self.in_unit(Unit::Kelvin)
+    }
+
+    /// Whether both are the same temperature, in whatever unit.
+    pub fn is_equivalent(self, other: Self) -> bool {
+        self.as_kelvin() == other.as_kelvin()
+    }
+}
+
+impl PartialEq for Temperature {
+    fn eq(&self, other: &Self) -> bool {
+        self.unit == other.unit && self.value == other.value
+    }
+}
+
+impl PartialOrd for Temperature {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        let by_kelvin = self.as_kelvin().partial_cmp(&other.as_kelvin())?;
+        let by_unit = (self.unit as u8).cmp(&(other.unit as u8));
+        Some(
+            by_kelvin
+                .then(by_unit)
+                .then(self.value.partial_cmp(&other.value)?),
+        )
+    }
+}
+
+impl fmt::Display for Temperature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.value, self.unit.symbol())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_between_units() {
+        let frost = Temperature::celsius(-5.5);
+        assert_eq!(frost.as_fahrenheit(), 22.1);
+        assert_eq!(frost.to(Unit::Kelvin).value(), 267.65);
+        assert_eq!(Temperature::fahrenheit(212.0).as_celsius(), 100.0);
+        assert_eq!(Temperature::kelvin(0.0).as_celsius(), -273.15);
+
+        assert!(Temperature::celsius(0.0).is_equivalent(Temperature::fahrenheit(32.0)));
+        assert!(Temperature::celsius(-5.5) < Temperature::kelvin(273.15));
+        assert_eq!(frost.to_string(), "-5.5 °C");
+    }
+
+    #[test]
+    fn equality_is_structural_and_consistent_with_order() {
+        let zero = Temperature::celsius(0.0);
+        let tiny = Temperature::celsius(1e-14);
+        let freezing = Temperature::kelvin(273.15);
+        assert_ne!(zero, freezing);
+        assert_ne!(freezing, tiny);
+        assert_ne!(zero, tiny);
+        assert!(zero.is_equivalent(freezing));
+        assert_ne!(zero, Temperature::fahrenheit(32.0));
+
+        // all three convert to 273.15 K, yet are ordered transitively
+        assert!(zero < tiny);
+        assert!(tiny < freezing);
+        assert!(zero < freezing);
+        assert_eq!(zero.partial_cmp(&zero), Some(Ordering::Equal));
+        assert_ne!(
+            Temperature::celsius(f64::NAN),
+            Temperature::celsius(f64::NAN)
+        );
+    }
+}